@@ -0,0 +1,85 @@
+use std::fmt;
+
+use system::system::GBSystem;
+
+//the switchable ROM bank
+const BANKED_START : u16 = 0x4000;
+const BANKED_END : u16 = 0x7fff;
+
+//an address for the debugger. Addresses in the switchable ROM area may name the bank
+//they mean, written bank:addr in hex.
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash)]
+pub struct BankAddr {
+	pub bank : Option<u16>,
+	pub addr : u16
+}
+
+impl BankAddr {
+
+	pub fn new(addr : u16) -> BankAddr {
+		BankAddr { bank : None, addr : addr }
+	}
+
+	pub fn parse(text : &str) -> Result<BankAddr, String> {
+		let mut parts = text.splitn(2, ':');
+		let first = parts.next().unwrap();
+		let (bank, addr) = match parts.next() {
+			Some(addr) => (Some(try!(u16::from_str_radix(first, 16).map_err(|e| format!("invalid bank {}: {}", first, e)))), addr),
+			None => (None, first)
+		};
+		let addr = try!(u16::from_str_radix(addr, 16).map_err(|e| format!("invalid address {}: {}", addr, e)));
+		BankAddr::with_bank(bank, addr)
+	}
+
+	//checks that only banked addresses get a bank
+	pub fn with_bank(bank : Option<u16>, addr : u16) -> Result<BankAddr, String> {
+		match bank {
+			//0000-3fff is always bank 0
+			Some(b) if b != 0 && !is_banked(addr) => Err(format!("{:>04x} isn't in switchable ROM (4000-7fff)", addr)),
+			_ => Ok(BankAddr { bank : if is_banked(addr) { bank } else { None }, addr : addr })
+		}
+	}
+
+	//`addr` in whatever bank is mapped right now
+	pub fn current(sys : &GBSystem, addr : u16) -> BankAddr {
+		BankAddr { bank : if is_banked(addr) { Some(sys.mbc.rom_bank() as u16) } else { None }, addr : addr }
+	}
+
+	//fill in the current bank if none was given
+	pub fn resolve(&self, sys : &GBSystem) -> BankAddr {
+		match self.bank {
+			Some(_) => *self,
+			None => BankAddr::current(sys, self.addr)
+		}
+	}
+
+	//true if the CPU sees this address at `addr` right now
+	pub fn is_mapped(&self, sys : &GBSystem) -> bool {
+		self.bank.map_or(true, |b| b == sys.mbc.rom_bank() as u16)
+	}
+
+	pub fn offset(&self, delta : u16) -> BankAddr {
+		BankAddr { bank : self.bank, addr : self.addr.wrapping_add(delta) }
+	}
+
+	//read without side effects or watchpoints. Banks that don't exist read ff.
+	pub fn read(&self, sys : &mut GBSystem) -> u8 {
+		match self.bank {
+			Some(b) if is_banked(self.addr) => sys.mbc.read_rom_bank(b as usize, self.addr).unwrap_or(0xff),
+			_ => sys.peek8(self.addr)
+		}
+	}
+}
+
+pub fn is_banked(addr : u16) -> bool {
+	addr >= BANKED_START && addr <= BANKED_END
+}
+
+impl fmt::Display for BankAddr {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match self.bank {
+			Some(b) => write!(f, "{:>02x}:{:>04x}", b, self.addr),
+			None => write!(f, "{:>04x}", self.addr)
+		}
+	}
+}
@@ -0,0 +1,90 @@
+use super::memory::Memory;
+use super::icache::ICache;
+use super::history::MemWrite;
+
+//one machine cycle
+pub const M_CYCLE : u32 = 4;
+
+//Memory as seen by a running instruction. Every access takes one M-cycle and the
+//system is advanced before the access happens, so the timer, PPU and DMA see reads
+//and writes at the right time within the instruction.
+pub struct Bus<'a, M: 'a> {
+	mem : &'a mut M,
+	//writes drop decoded instructions
+	icache : &'a mut ICache,
+	//what writes replaced, for the history
+	log : Option<&'a mut Vec<MemWrite>>,
+	//clock cycles the system was advanced by so far
+	pub cycles : u32
+}
+
+impl<'a, M: Memory> Bus<'a, M> {
+
+	pub fn new(mem : &'a mut M, icache : &'a mut ICache) -> Bus<'a, M> {
+		Bus::logged(mem, icache, None)
+	}
+
+	pub fn logged(mem : &'a mut M, icache : &'a mut ICache, log : Option<&'a mut Vec<MemWrite>>) -> Bus<'a, M> {
+		Bus {
+			mem : mem,
+			icache : icache,
+			log : log,
+			cycles : 0
+		}
+	}
+
+	pub fn tick(&mut self, cycles : u32) {
+		self.mem.update(cycles);
+		self.cycles += cycles;
+	}
+
+	//an internal cycle without memory access
+	pub fn idle(&mut self) {
+		self.tick(M_CYCLE);
+	}
+
+	//advance the system by whatever is left of an instruction taking `total` cycles
+	pub fn finish(&mut self, total : u32) {
+		if total > self.cycles {
+			let remaining = total - self.cycles;
+			self.tick(remaining);
+		}
+	}
+
+	//access without taking time, for peeking at registers the CPU sees directly
+	pub fn untimed(&mut self) -> &mut M {
+		self.mem
+	}
+
+	//the CPU pushes the high byte first
+	pub fn push16(&mut self, sp : u16, data : u16) {
+		self.write8(sp.wrapping_add(1), (data >> 8) as u8);
+		self.write8(sp, data as u8);
+	}
+}
+
+impl<'a, M: Memory> Memory for Bus<'a, M> {
+
+	fn read8(&mut self, addr : u16) -> u8 {
+		self.idle();
+		self.mem.read8(addr)
+	}
+
+	fn write8(&mut self, addr : u16, data : u8) {
+		self.idle();
+		if let Some(ref mut log) = self.log {
+			let old = self.mem.peek8(addr);
+			log.push(MemWrite { addr : addr, old : old, new : data });
+		}
+		self.mem.write8(addr, data);
+		self.icache.invalidate(addr);
+	}
+
+	fn peek8(&mut self, addr : u16) -> u8 {
+		self.mem.peek8(addr)
+	}
+
+	fn switch_speed(&mut self) -> Option<bool> {
+		self.mem.switch_speed()
+	}
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+
+use address::{self, BankAddr};
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum CallKind {
+	Call,
+	Rst,
+	Interrupt
+}
+
+//a return address the CPU pushed
+#[derive(Debug,Copy,Clone)]
+pub struct Frame {
+	pub kind : CallKind,
+	//the call instruction, or the instruction an interrupt came before
+	pub site : u16,
+	pub target : u16,
+	pub return_addr : u16,
+	//where the return address is on the stack
+	pub sp : u16,
+	//the ROM bank mapped at 4000-7fff at the time
+	pub bank : Option<u16>
+}
+
+impl Frame {
+
+	pub fn site_addr(&self) -> BankAddr {
+		self.in_bank(self.site)
+	}
+
+	pub fn target_addr(&self) -> BankAddr {
+		self.in_bank(self.target)
+	}
+
+	fn in_bank(&self, addr : u16) -> BankAddr {
+		BankAddr { bank : if address::is_banked(addr) { self.bank } else { None }, addr : addr }
+	}
+}
+
+#[derive(Debug,Copy,Clone)]
+pub enum StackProblem {
+	//a return that doesn't go back to where the innermost call came from
+	MismatchedReturn { at : u16, to : u16, expected : u16 },
+	//the stack pointer moved above return addresses that were never returned to
+	Discarded { at : u16, sp : u16, frames : usize }
+}
+
+impl fmt::Display for StackProblem {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			StackProblem::MismatchedReturn { at, to, expected } =>
+				write!(f, "return at {:>04x} goes to {:>04x}, the caller expects {:>04x}", at, to, expected),
+			StackProblem::Discarded { at, sp, frames } =>
+				write!(f, "stack pointer {:>04x} at {:>04x} is above the return address of {} call(s)", sp, at, frames)
+		}
+	}
+}
+
+//the calls the CPU is in, from what it pushed and popped as return addresses.
+//Code that switches stacks or returns through tricks shows up as problems.
+pub struct CallStack {
+	pub frames : Vec<Frame>,
+	//only collected when someone looks at them
+	pub report_problems : bool,
+	pub problems : Vec<StackProblem>
+}
+
+impl CallStack {
+
+	pub fn new() -> CallStack {
+		CallStack {
+			frames : Vec::new(),
+			report_problems : false,
+			problems : Vec::new()
+		}
+	}
+
+	pub fn clear(&mut self) {
+		self.frames.clear();
+	}
+
+	pub fn depth(&self) -> usize {
+		self.frames.len()
+	}
+
+	//after pushing the return address to `frame.sp`
+	pub fn call(&mut self, frame : Frame) {
+		self.discard_below(frame.site, frame.sp.wrapping_add(2));
+		self.frames.push(frame);
+	}
+
+	//`sp` is where the return address `to` was popped from
+	pub fn ret(&mut self, at : u16, sp : u16, to : u16) {
+		self.discard_below(at, sp);
+		let (expected, popped) = match self.frames.last() {
+			//nothing to check against, like after loading a state
+			None => return,
+			Some(f) => (f.return_addr, f.sp == sp)
+		};
+		if popped {
+			self.frames.pop();
+		}
+		//with something else left on the stack it's a jump rather than a return, keep the frame
+		if !popped || to != expected {
+			self.report(StackProblem::MismatchedReturn { at : at, to : to, expected : expected });
+		}
+	}
+
+	//frames whose return address is below the stack pointer can't be returned to any more
+	fn discard_below(&mut self, at : u16, sp : u16) {
+		let live = self.frames.iter().rposition(|f| f.sp >= sp).map_or(0, |i| i + 1);
+		let discarded = self.frames.len() - live;
+		if discarded > 0 {
+			self.frames.truncate(live);
+			self.report(StackProblem::Discarded { at : at, sp : sp, frames : discarded });
+		}
+	}
+
+	fn report(&mut self, problem : StackProblem) {
+		if self.report_problems {
+			self.problems.push(problem);
+		}
+	}
+}
@@ -1,6 +1,3 @@
-use std::sync::Arc;
-use std::cell::RefCell;
-
 use super::gb::GBRegisters;
 use core::operands::Reg16Operand;
 use super::memory::*;
@@ -8,33 +5,109 @@ use system::system::GBSystem;
 use std::thread;
 use std::time::Duration;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
+use std::fmt;
+use std::mem;
 use super::register::Contents;
+use super::execute::ExecuteError;
+use super::instruction::Instruction;
+use super::icache::{ICache, CachedInsn};
+#[cfg(feature = "jit")]
+use super::jit::Jit;
+use savestate::{SaveState, StateWriter, StateReader};
+use symbols::Symbols;
+use super::callstack::CallStack;
+use super::history::{self, History, Step, MemWrite};
+use super::instruction::InstructionType;
+use address::{self, BankAddr};
 
 use time;
 
-pub struct CPU {
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum EmulationError {
+	//undefined opcode. The real CPU hangs until it is reset, so do we.
+	InvalidOpcode { pc : u16, opcode : u8 },
+	//the decoder produced something execute() can't handle
+	Execute { pc : u16, error : ExecuteError }
+}
+
+impl EmulationError {
+	pub fn pc(&self) -> u16 {
+		match *self {
+			EmulationError::InvalidOpcode { pc, .. } | EmulationError::Execute { pc, .. } => pc
+		}
+	}
+}
+
+impl fmt::Display for EmulationError {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			EmulationError::InvalidOpcode { pc, opcode } => write!(f, "CPU locked up on invalid opcode {:02x} at {:04x}", opcode, pc),
+			EmulationError::Execute { pc, error } => write!(f, "couldn't execute instruction at {:04x}: {:?}", pc, error)
+		}
+	}
+}
+
+//4.194304 MHz
+pub const CLK_PERIOD_NS : f64 = 238.418579;
+const JOYPAD_ADDR : u16 = 0xff00;
+
+pub fn clk_period_ns(double_speed : bool) -> f64 {
+	if double_speed { CLK_PERIOD_NS / 2.0 } else { CLK_PERIOD_NS }
+}
+
+pub struct CPU<M = GBSystem> {
 
     pub regs : GBRegisters,
-    pub sys : Arc<RefCell<GBSystem>>,
+    pub sys : M,
     pub halt_mode : bool,
     pub stop_mode : bool,
+    pub locked_up : bool,
+    //instructions left until EI takes effect
+    pub ei_delay : u8,
+    //the next instruction is fetched without incrementing PC
+    pub halt_bug : bool,
     
     pub clk_period_ns : f64,
+    pub cycles : u64,
+    
+    //decoded instructions. Whoever writes to memory behind the CPU's back has to invalidate it.
+    pub icache : ICache,
+    
+    //return addresses pushed by calls and interrupts, for the debugger
+    pub call_stack : CallStack,
+    //the last instructions executed, None if not recorded
+    pub history : Option<History>,
+    
+    //translated code, None runs everything in the interpreter
+    #[cfg(feature = "jit")]
+    pub jit : Option<Box<Jit>>,
     
-    pub trace_file : Option<File>
+    pub trace_file : Option<File>,
+    //labels for the trace and the debugger
+    pub symbols : Symbols
 }
 
-impl CPU {
+impl<M: Memory> CPU<M> {
 	
-	pub fn new(sys : Arc<RefCell<GBSystem>>) -> CPU {
+	pub fn new(sys : M) -> CPU<M> {
 	    let mut cpu = CPU {
 	        regs : GBRegisters::new(),
 	        sys : sys,
 	        halt_mode : false,
 	        stop_mode : false,
-	        clk_period_ns : 238.418579,
-	        trace_file : None
+	        locked_up : false,
+	        ei_delay : 0,
+	        halt_bug : false,
+	        clk_period_ns : CLK_PERIOD_NS,
+	        cycles : 0,
+	        icache : ICache::new(),
+	        call_stack : CallStack::new(),
+	        history : None,
+	        #[cfg(feature = "jit")]
+	        jit : None,
+	        trace_file : None,
+	        symbols : Symbols::new()
 	    };
 	    cpu.reset();
 	    cpu
@@ -45,25 +118,70 @@ impl CPU {
 	}
 	
 	pub fn fetch(&mut self, addr: u16) -> [u8; 3] {
-		let mut mem = self.sys.borrow_mut();
-		[mem.read8(addr), mem.read8(addr.wrapping_add(1)), mem.read8(addr.wrapping_add(2))]
+		let mem = &mut self.sys;
+		[mem.peek8(addr), mem.peek8(addr.wrapping_add(1)), mem.peek8(addr.wrapping_add(2))]
 	} 
 	
-	//run the next instruction
-	pub fn run_instruction(&mut self) -> f64 {
+	//decode the instruction at `pc`, reusing an earlier decode if the code can't have changed since
+	fn fetch_decoded(&mut self, pc: u16) -> (Instruction, [u8; 3]) {
+		let bank = self.sys.code_bank(pc);
+		if let Some(bank) = bank {
+			if let Some(cached) = self.icache.get(pc, bank) {
+				return (cached.insn, cached.bytes)
+			}
+		}
+		let insn_bytes = self.fetch(pc);
+		let insn = self.decode(insn_bytes);
+		if let Some(bank) = bank {
+			self.icache.insert(pc, bank, CachedInsn { insn : insn, bytes : insn_bytes });
+		}
+		(insn, insn_bytes)
+	}
+	
+	//run the next instruction and return the emulated time in ns.
+	//After an error the CPU stays locked up, but the rest of the system keeps running.
+	pub fn run_instruction(&mut self) -> Result<f64, EmulationError> {
 		
 		let mut delta_cycles = 4;
-		if !(self.halt_mode || self.stop_mode) {
-			//fetch instruction
+		let mut executed = false;
+		let mut fault = None;
+		let mut step = if self.history.is_some() && !self.locked_up && !self.stop_mode {
+			Some(self.begin_step())
+		} else {
+			None
+		};
+		if self.locked_up {
+			//nothing but a reset gets us out of here
+		} else if self.stop_mode {
+			//a pressed button in a selected row ends STOP. Unlike real hardware the clocks
+			//keep running, the frontend relies on frames to deliver input.
+			if self.sys.peek8(JOYPAD_ADDR) & 0x0f != 0x0f {
+				self.stop_mode = false;
+			}
+		} else if let Some(c) = self.run_compiled_block() {
+			executed = true;
+			delta_cycles = c;
+		} else if !self.halt_mode {
+			//fetch and decode instruction
 			let pc = self.regs.pc;
-			let insn_bytes = self.fetch(pc);
-
-			//decode insn
-			let insn = self.decode(insn_bytes);
+			let (insn, insn_bytes) = if self.halt_bug {
+				//PC failed to increment after the first byte, so it is read twice
+				self.halt_bug = false;
+				let bytes = self.fetch(pc);
+				let insn_bytes = [bytes[0], bytes[0], bytes[1]];
+				self.regs.pc = pc.wrapping_sub(1);
+				(self.decode(insn_bytes), insn_bytes)
+			} else {
+				self.fetch_decoded(pc)
+			};
 			
 			//print raw and decoded instruction
 			if let Some(ref mut tracefile) = self.trace_file {
 				let mut trace_line = String::with_capacity(128);
+				let here = BankAddr { bank : if address::is_banked(pc) { self.sys.code_bank(pc) } else { None }, addr : pc };
+				if let Some(label) = self.symbols.name_at(here) {
+					trace_line.push_str(&format!("{}:\n", label));
+				}
 				//print register contents and pc
 				trace_line.push_str(&format!("{} | {:04x}:", self.regs, pc));
 				for i in 0..3 {
@@ -73,31 +191,134 @@ impl CPU {
 						trace_line.push_str("   ");
 					}
 				}
-				trace_line.push_str(&format!(" {}\n", insn));	
+				trace_line.push_str(&format!(" {}", insn));
+				if let Some(label) = self.symbols.operand_name(&insn, here, self.sys.code_bank(0x4000).unwrap_or(1)) {
+					trace_line.push_str(&format!(" ; {}", label));
+				}
+				trace_line.push('\n');
 				tracefile.write_all(trace_line.as_bytes()).unwrap();
 			}
 			//execute insn
-			delta_cycles = match self.execute(insn) {
-				Ok(c) => c,
-				Err(e) => {
-					panic!("\n Fatal error during execution: {:?}", e);
+			executed = true;
+			if let Some(ref mut step) = step {
+				step.insn = Some(insn);
+				match insn.itype {
+					InstructionType::call | InstructionType::rst | InstructionType::ret | InstructionType::reti => step.call_frames = Some(self.call_stack.frames.clone()),
+					_ => {}
 				}
 			}
+			match self.execute(insn) {
+				Ok(c) => delta_cycles = c,
+				Err(e) => fault = Some(self.fault(pc, insn_bytes[0], e))
+			}
+		}
+		//EI takes effect after the instruction following it
+		if self.ei_delay > 0 && !self.locked_up {
+			self.ei_delay -= 1;
+			if self.ei_delay == 0 {
+				self.regs.ime = true;
+			}
+		}
+		
+		//update periphery. Instructions do that themselves while they execute.
+		if !executed {
+			self.sys.update(delta_cycles);
 		}
-		//update periphery
-		self.sys.borrow_mut().update(delta_cycles);
 		
 		//handle interrupts
-		let interrupt_cycles = match self.handle_interrupts() {
-			Some(c) => {
-				//update again if necessary
-				self.sys.borrow_mut().update(c);
-				c
-			},
-			None => 0
-		};
+		let interrupt_cycles = if self.locked_up { None } else { self.handle_interrupts() };
+		let interrupt_cycles = interrupt_cycles.unwrap_or(0);
+		self.cycles += (delta_cycles + interrupt_cycles) as u64;
+		if let Some(mut step) = step {
+			let history = self.history.as_mut().unwrap();
+			step.writes = mem::replace(&mut history.writes, Vec::new());
+			if executed || interrupt_cycles > 0 {
+				history.push(step);
+			}
+		}
+		if let Some(e) = fault {
+			return Err(e)
+		}
 		//return simulation time
-		self.clk_period_ns * ((delta_cycles + interrupt_cycles) as f64)
+		Ok(self.clk_period_ns * ((delta_cycles + interrupt_cycles) as f64))
+	}
+	
+	//keep the last `capacity` instructions, for looking back and stepping backwards
+	pub fn enable_history(&mut self, capacity : usize) {
+		self.history = Some(History::new(capacity));
+	}
+	
+	fn begin_step(&mut self) -> Step {
+		let pc = self.regs.pc;
+		Step {
+			addr : BankAddr { bank : if address::is_banked(pc) { self.sys.code_bank(pc) } else { None }, addr : pc },
+			insn : None,
+			regs : self.regs,
+			halt_mode : self.halt_mode,
+			ei_delay : self.ei_delay,
+			halt_bug : self.halt_bug,
+			call_depth : self.call_stack.depth(),
+			call_frames : None,
+			writes : Vec::new()
+		}
+	}
+	
+	//undo the last recorded instruction. Returns it with the writes that couldn't be undone,
+	//see history::can_undo. Timers, video and the rest of the system aren't rewound.
+	pub fn reverse_step(&mut self) -> Option<(Step, Vec<MemWrite>)> {
+		let step = match self.history.as_mut().and_then(|h| h.pop()) {
+			Some(s) => s,
+			None => return None
+		};
+		let mut skipped = Vec::new();
+		for w in step.writes.iter().rev() {
+			if history::can_undo(w.addr) {
+				self.sys.write8(w.addr, w.old);
+				self.icache.invalidate(w.addr);
+			} else {
+				skipped.push(*w);
+			}
+		}
+		self.regs = step.regs;
+		self.halt_mode = step.halt_mode;
+		self.stop_mode = false;
+		self.locked_up = false;
+		self.ei_delay = step.ei_delay;
+		self.halt_bug = step.halt_bug;
+		match step.call_frames {
+			Some(ref frames) => self.call_stack.frames = frames.clone(),
+			None => self.call_stack.frames.truncate(step.call_depth)
+		}
+		Some((step, skipped))
+	}
+	
+	#[cfg(feature = "jit")]
+	fn run_compiled_block(&mut self) -> Option<u32> {
+		self.run_compiled()
+	}
+	
+	#[cfg(not(feature = "jit"))]
+	fn run_compiled_block(&mut self) -> Option<u32> {
+		None
+	}
+	
+	//without the jit feature everything is interpreted
+	#[cfg(not(feature = "jit"))]
+	pub fn enable_jit(&mut self) {
+	}
+	
+	#[cfg(not(feature = "jit"))]
+	pub fn disable_jit(&mut self) {
+	}
+	
+	//lock up on an instruction we couldn't execute. PC stays at the faulting instruction.
+	pub fn fault(&mut self, pc : u16, opcode : u8, error : ExecuteError) -> EmulationError {
+		self.locked_up = true;
+		self.regs.pc = pc;
+		match error {
+			ExecuteError::InvalidInstruction => EmulationError::InvalidOpcode { pc : pc, opcode : opcode },
+			_ => EmulationError::Execute { pc : pc, error : error }
+		}
 	}
 	
 	pub fn reset(&mut self) {
@@ -108,5 +329,59 @@ impl CPU {
 	    self.regs.hl = 0x014d;
 	    self.regs.sp = 0xfffe;
 	    self.regs.ime = false;
+	    self.halt_mode = false;
+	    self.stop_mode = false;
+	    self.locked_up = false;
+	    self.ei_delay = 0;
+	    self.halt_bug = false;
+	    self.call_stack.clear();
+	    if let Some(ref mut history) = self.history {
+	    	history.clear();
+	    }
+	    self.flush_code_caches();
+	}
+	
+	//forget decoded and translated code, after changing memory the CPU doesn't watch, like ROM
+	pub fn flush_code_caches(&mut self) {
+	    self.icache.clear();
+	    #[cfg(feature = "jit")]
+	    {
+	    	if let Some(ref mut jit) = self.jit {
+	    		jit.flush();
+	    	}
+	    }
+	}
+}
+
+//the system is saved separately, see savestate::save
+impl<M: Memory> SaveState for CPU<M> {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		self.regs.save_state(w);
+		w.write_bool(self.halt_mode);
+		w.write_bool(self.stop_mode);
+		w.write_bool(self.locked_up);
+		w.write_u8(self.ei_delay);
+		w.write_bool(self.halt_bug);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		try!(self.regs.load_state(r));
+		self.halt_mode = try!(r.read_bool());
+		self.stop_mode = try!(r.read_bool());
+		self.locked_up = if r.version >= 2 { try!(r.read_bool()) } else { false };
+		if r.version >= 3 {
+			self.ei_delay = try!(r.read_u8());
+			self.halt_bug = try!(r.read_bool());
+		} else {
+			self.ei_delay = 0;
+			self.halt_bug = false;
+		}
+		//what was called before the state was saved isn't known
+		self.call_stack.clear();
+		if let Some(ref mut history) = self.history {
+			history.clear();
+		}
+		Ok(())
 	}
 }
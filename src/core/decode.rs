@@ -39,8 +39,7 @@ macro_rules! insn {
     })
 }
 
-//impl<M: Memory> CPU<M> {
-impl CPU {
+impl<M: Memory> CPU<M> {
     pub fn decode(&self, opcode : [u8; 3]) -> Instruction {
         
         let nn : u16 = ((opcode[2] as u16) << 8) | (opcode[1] as u16); //little endian
@@ -63,7 +62,7 @@ impl CPU {
             0x0d => insn!(dec, reg8(c)),
             0x0e => insn!(ld, reg8(c), imm8(n)),
             0x0f => insn!(rrca),
-            0x10 => insn!(stop),
+            0x10 => Instruction { length: 2, ..insn!(stop)}, //the second byte is skipped
             0x11 => insn!(ld, reg16(de), imm16(nn)), 
             0x12 => insn!(ld, mem_reg(de), reg8(a)),
             0x13 => insn!(inc, reg16(de)),
@@ -1,15 +1,18 @@
-use super::cpu::CPU;
+use super::cpu::{CPU, clk_period_ns};
+use super::interrupt::{IF_ADDR, IE_ADDR};
 use super::gb::*;
 use super::memory::*;
+use super::bus::{Bus, M_CYCLE};
 use super::instruction::*;
 use super::instruction::InstructionType::*;
 use super::operands::*;
 use super::operands::Operand::*;
 use super::register::Contents;
+use super::callstack::{Frame, CallKind};
 use self::ExecuteError::*;
 
-#[derive(Debug)]
-enum ExecuteError {
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ExecuteError {
 	InvalidDestOperand(Operand),
 	InvalidSrcOperand(Operand),
 	InvalidInstruction
@@ -112,17 +115,22 @@ macro_rules! try_r16 {
 	}))
 }
 
-//impl<M: Memory> CPU<M> {
-impl CPU {
+impl<M: Memory> CPU<M> {
 	
+    //execute a fetched instruction. The system is advanced along with the memory accesses,
+    //so the returned cycles have already elapsed when this returns.
     pub fn execute(&mut self, insn: Instruction) -> Result<u32, ExecuteError> {
         
         let regs = &mut self.regs;
-        let mut mem = self.sys.borrow_mut();
+        let mem = &mut Bus::logged(&mut self.sys, &mut self.icache, self.history.as_mut().map(|h| &mut h.writes));
+        let calls = &mut self.call_stack;
         
         let mut cycles = 4;
         let mut next_pc = regs.pc.wrapping_add(insn.length as u16);
         
+        //one M-cycle for each byte of the instruction
+        mem.tick(M_CYCLE * insn.length as u32);
+        
         cycles = try!(match insn.itype {
             //Loadcommands
             ld => {
@@ -208,7 +216,8 @@ impl CPU {
             push => {
             	let reg = try_r16!(insn.src[0], InvalidSrcOperand);
         		regs.sp = regs.sp.wrapping_sub(2);
-            	mem.write16(regs.sp, regs.get16(reg));
+        		mem.idle();
+            	mem.push16(regs.sp, regs.get16(reg));
             	Ok(16)
             },
             pop => {
@@ -407,21 +416,38 @@ impl CPU {
             },
             nop => Ok(4),
             halt => {
-            	//println!("entering halt mode");
-            	self.halt_mode = true;
+            	let pending = mem.untimed().peek8(IF_ADDR) & mem.untimed().peek8(IE_ADDR) & 0x1f != 0;
+            	if regs.ime || !pending {
+            		self.halt_mode = true;
+            	} else if self.ei_delay > 0 {
+            		//EI right before HALT: the interrupt is taken now and returns to the HALT
+            		next_pc = regs.pc;
+            	} else {
+            		//HALT bug: HALT is skipped and the next byte is read twice
+            		self.halt_bug = true;
+            	}
             	Ok(4)
             },
             stop => {
-            	//println!("entering stop mode");
-            	self.stop_mode = true;
+            	//a prepared CGB speed switch replaces entering STOP mode
+            	match mem.switch_speed() {
+            		Some(double_speed) => self.clk_period_ns = clk_period_ns(double_speed),
+            		None => self.stop_mode = true
+            	}
+            	//STOP resets the divider
+            	mem.untimed().reset_divider();
             	Ok(4)
             },
             di => {
             	regs.ime = false;
+            	self.ei_delay = 0;
             	Ok(4)
             },
             ei => {
-            	regs.ime = true;
+            	//IME is set after the next instruction
+            	if !regs.ime {
+            		self.ei_delay = 2;
+            	}
             	Ok(4)
             },
             //branches
@@ -456,20 +482,29 @@ impl CPU {
             call => {
             	if regs.cc_satisfied(insn.cc) {
             		regs.sp = regs.sp.wrapping_sub(2);
-					mem.write16(regs.sp, next_pc);
+            		mem.idle();
+					mem.push16(regs.sp, next_pc);
+					let return_addr = next_pc;
 					next_pc = try!(match insn.src[0] {
                     	imm16(addr) => Ok(addr),
                     	_ =>  Err(ExecuteError::InvalidSrcOperand(insn.src[0]))
                 	});
+                	calls.call(Frame { kind : CallKind::Call, site : regs.pc, target : next_pc, return_addr : return_addr,
+                					   sp : regs.sp, bank : mem.untimed().code_bank(0x4000) });
             		Ok(24)
             	} else {
             		Ok(12)
             	}
             },
             ret | reti=> {            	
+            	if insn.cc != CCOperand::none {
+            		//checking the condition takes a cycle
+            		mem.idle();
+            	}
             	if regs.cc_satisfied(insn.cc) {
             		cycles = 16;
             		next_pc = mem.read16(regs.sp);
+            		calls.ret(regs.pc, regs.sp, next_pc);
 					regs.sp = regs.sp.wrapping_add(2);
             		
             		if insn.cc != CCOperand::none {
@@ -487,17 +522,24 @@ impl CPU {
             },
             rst => {
         		regs.sp = regs.sp.wrapping_sub(2);
-				mem.write16(regs.sp, next_pc);
+        		mem.idle();
+				mem.push16(regs.sp, next_pc);
 				
+				let return_addr = next_pc;
 				next_pc = try!(match insn.src[0] {
                 	imm8(addr) => Ok(addr as u16),
                 	_ =>  Err(ExecuteError::InvalidSrcOperand(insn.src[0]))
             	});
+            	calls.call(Frame { kind : CallKind::Rst, site : regs.pc, target : next_pc, return_addr : return_addr,
+            					   sp : regs.sp, bank : mem.untimed().code_bank(0x4000) });
         		Ok(16)
             },
-            invalid => Ok(4)/*Err(InvalidInstruction)*/
+            invalid => Err(InvalidInstruction)
         });
         
+        //internal cycles at the end of the instruction
+        mem.finish(cycles);
+        
         //update program counter
         regs.pc = next_pc;
         
@@ -1,15 +1,20 @@
 use std::fmt;
+use std::io;
 
 use super::register::*;
 use super::operands::*;
 use super::operands::Reg8Operand::*;
 use super::operands::Reg16Operand::*;
+use savestate::{SaveState, StateWriter, StateReader};
 
 pub const CARRY_FLAG : i32 = 4;
 pub const HALFCARRY_FLAG : i32 = 5;
 pub const SUB_FLAG : i32 = 6;
 pub const ZERO_FLAG : i32 = 7;
 
+//the JIT addresses registers by their offset
+#[repr(C)]
+#[derive(Clone,Copy)]
 pub struct GBRegisters {
     pub af : Register,
     pub bc : Register,
@@ -127,4 +132,22 @@ impl fmt::Display for GBRegisters {
                  self.h_flag() as usize,self.c_flag()  as usize,
         		 self.ime as usize)
     }
-}
\ No newline at end of file
+}
+
+impl SaveState for GBRegisters {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		for reg in &[self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+			w.write_u16(*reg);
+		}
+		w.write_bool(self.ime);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		for reg in &mut [&mut self.af, &mut self.bc, &mut self.de, &mut self.hl, &mut self.sp, &mut self.pc] {
+			**reg = try!(r.read_u16());
+		}
+		self.ime = try!(r.read_bool());
+		Ok(())
+	}
+}
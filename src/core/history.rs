@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::gb::GBRegisters;
+use super::instruction::Instruction;
+use super::callstack::Frame;
+use address::BankAddr;
+
+//a byte the CPU wrote and what was there before
+#[derive(Debug,Copy,Clone)]
+pub struct MemWrite {
+	pub addr : u16,
+	pub old : u8,
+	pub new : u8
+}
+
+//everything needed to show and undo one instruction, and the interrupt dispatched after it
+#[derive(Clone)]
+pub struct Step {
+	pub addr : BankAddr,
+	//None if the CPU was halted and only dispatched an interrupt
+	pub insn : Option<Instruction>,
+	//the state before
+	pub regs : GBRegisters,
+	pub halt_mode : bool,
+	pub ei_delay : u8,
+	pub halt_bug : bool,
+	pub call_depth : usize,
+	//the whole call stack, only for instructions that change more than its top
+	pub call_frames : Option<Vec<Frame>>,
+	pub writes : Vec<MemWrite>
+}
+
+impl fmt::Display for Step {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f, "{} | {}: ", self.regs, self.addr));
+		match self.insn {
+			Some(insn) => try!(write!(f, "{}", insn)),
+			None => try!(write!(f, "(halted)"))
+		}
+		for w in &self.writes {
+			try!(write!(f, " ({:>04x}) {:>02x}->{:>02x}", w.addr, w.old, w.new));
+		}
+		Ok(())
+	}
+}
+
+//undoing a write to these runs the hardware behind them again, bank switches, DMA,
+//timer resets and so on. They are left alone.
+pub fn can_undo(addr : u16) -> bool {
+	match addr {
+		0x0000 ... 0x7fff | 0xff00 ... 0xff7f | 0xffff => false,
+		_ => true
+	}
+}
+
+//the last executed instructions, oldest first
+pub struct History {
+	steps : VecDeque<Step>,
+	capacity : usize,
+	//writes of the instruction running now
+	pub writes : Vec<MemWrite>
+}
+
+impl History {
+
+	pub fn new(capacity : usize) -> History {
+		History {
+			steps : VecDeque::with_capacity(capacity),
+			capacity : capacity,
+			writes : Vec::new()
+		}
+	}
+
+	pub fn push(&mut self, step : Step) {
+		if self.capacity == 0 {
+			return
+		}
+		if self.steps.len() == self.capacity {
+			self.steps.pop_front();
+		}
+		self.steps.push_back(step);
+	}
+
+	pub fn pop(&mut self) -> Option<Step> {
+		self.steps.pop_back()
+	}
+
+	pub fn clear(&mut self) {
+		self.steps.clear();
+		self.writes.clear();
+	}
+
+	pub fn len(&self) -> usize {
+		self.steps.len()
+	}
+
+	//the last `n` steps, oldest first
+	pub fn last(&self, n : usize) -> Vec<&Step> {
+		let skip = self.steps.len().saturating_sub(n);
+		self.steps.iter().skip(skip).collect()
+	}
+}
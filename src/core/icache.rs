@@ -0,0 +1,79 @@
+use super::instruction::Instruction;
+
+//a decoded instruction and the bytes it was decoded from
+#[derive(Copy,Clone)]
+pub struct CachedInsn {
+	pub insn : Instruction,
+	pub bytes : [u8; 3]
+}
+
+type Entry = Option<(u16, CachedInsn)>;
+
+const PAGE_SIZE : usize = 0x100;
+
+//Decoded instructions by address. Each entry is tagged with the bank mapped at its address
+//when it was decoded, so bank switches don't need to flush anything. Code in RAM is
+//dropped when the CPU writes to it.
+//Entries are per instruction rather than per block so the interpreter can still stop anywhere.
+//They are kept in pages of 256 that are only allocated once code runs there, a game touches a
+//few dozen of them and a CPU that runs single instructions only one or two.
+pub struct ICache {
+	pages : Vec<Option<Box<[Entry; PAGE_SIZE]>>>
+}
+
+impl ICache {
+
+	pub fn new() -> ICache {
+		ICache {
+			pages : (0..0x10000 / PAGE_SIZE).map(|_| None).collect()
+		}
+	}
+
+	#[inline]
+	pub fn get(&self, pc : u16, bank : u16) -> Option<CachedInsn> {
+		match self.pages[pc as usize / PAGE_SIZE] {
+			Some(ref page) => match page[pc as usize % PAGE_SIZE] {
+				Some((b, insn)) if b == bank => Some(insn),
+				_ => None
+			},
+			None => None
+		}
+	}
+
+	#[inline]
+	pub fn insert(&mut self, pc : u16, bank : u16, insn : CachedInsn) {
+		let page = self.pages[pc as usize / PAGE_SIZE].get_or_insert_with(|| Box::new([None; PAGE_SIZE]));
+		page[pc as usize % PAGE_SIZE] = Some((bank, insn));
+	}
+
+	//drop everything decoded from `addr`
+	pub fn invalidate(&mut self, addr : u16) {
+		if addr < 0x8000 {
+			//writes to ROM go to the MBC
+			return
+		}
+		self.invalidate_range(addr);
+		//echo RAM
+		match addr {
+			0xc000 ... 0xddff => self.invalidate_range(addr + 0x2000),
+			0xe000 ... 0xfdff => self.invalidate_range(addr - 0x2000),
+			_ => {}
+		}
+	}
+
+	//instructions are up to 3 bytes long, so one starting up to 2 bytes earlier covers `addr`
+	fn invalidate_range(&mut self, addr : u16) {
+		for i in 0..3 {
+			let pc = addr.wrapping_sub(i) as usize;
+			if let Some(ref mut page) = self.pages[pc / PAGE_SIZE] {
+				page[pc % PAGE_SIZE] = None;
+			}
+		}
+	}
+
+	pub fn clear(&mut self) {
+		for page in self.pages.iter_mut() {
+			*page = None;
+		}
+	}
+}
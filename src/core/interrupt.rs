@@ -1,66 +1,55 @@
 use super::cpu::CPU;
-use system::system::GBSystem;
+use super::memory::Memory;
+use super::bus::Bus;
+use super::callstack::{Frame, CallKind};
 
-impl CPU {
+pub const IF_ADDR : u16 = 0xff0f;
+pub const IE_ADDR : u16 = 0xffff;
+
+impl<M: Memory> CPU<M> {
 	
+	//dispatch the highest priority pending interrupt. Like execute, this advances the system
+	//by the returned number of cycles.
 	pub fn handle_interrupts(&mut self) -> Option<u32> {
-		//only handle interrupts when interrupts are enabled or we're in halt mode
-		if self.regs.ime || self.halt_mode {
-			let (iflags,  ienable);
-			{
-				let sys = self.sys.borrow();
-				let iregs = sys.interrupt_regs.borrow_mut();
-				iflags = *iregs.iflags;
-				ienable = *iregs.ienable;
-			}
+		let pending = self.sys.peek8(IF_ADDR) & self.sys.peek8(IE_ADDR) & 0x1f;
+		if pending == 0 {
+			return None
+		}
+		//a pending interrupt ends HALT even if it isn't serviced
+		self.halt_mode = false;
+		if !self.regs.ime {
+			return None
+		}
 
-			for i in 0..5 { //check from highest to lowest priority
-				
-				if iflags & ienable & (1<<i) != 0 {
-//					if self.halt_mode {
-//						println!("exiting halt mode!");
-						self.halt_mode = false;						
-//					}
-					
-					if !self.regs.ime {
-						//only possibility to reach this is through halt mode
-						//DMG bug: if IME==0 and halt_mode=true then the next byte after halt is executed twice...
-						let pc = self.regs.pc;
-						let mut insn_bytes = self.fetch(pc);
-						insn_bytes[2] = insn_bytes[1];
-						insn_bytes[1] = insn_bytes[0];
-						let insn = self.decode(insn_bytes);
-						let cycles = self.execute(insn).unwrap();
-						
-						return Some(cycles);
-					} else {
-						
-						let mut sys = self.sys.borrow_mut();
-						
-						//reset interrupt flag
-						sys.interrupt_regs.borrow_mut().iflags.data &= !(1<<i);
+		//disable interrupts
+		self.regs.ime = false;
+		let mut bus = Bus::logged(&mut self.sys, &mut self.icache, self.history.as_mut().map(|h| &mut h.writes));
+		bus.idle();
+		bus.idle();
+		//push pc to stack
+		let pc = self.regs.pc;
+		self.regs.sp = self.regs.sp.wrapping_sub(2);
+		bus.write8(self.regs.sp.wrapping_add(1), (pc >> 8) as u8);
+		//the interrupt is chosen only now, pushing the high byte may have overwritten IE
+		let pending = bus.untimed().peek8(IF_ADDR) & bus.untimed().peek8(IE_ADDR) & 0x1f;
+		bus.write8(self.regs.sp, pc as u8);
 
-						let isr_addr = match i {
-							0 => 0x40,
-							1 => 0x48,
-							2 => 0x50,
-							3 => 0x58,
-							4 => 0x60,
-							_ => unreachable!()
-						};					
-						//disable interrupts
-						self.regs.ime = false;
-						//push pc to stack
-						self.regs.sp -= 2;
-						sys.write16(self.regs.sp, self.regs.pc);
-						self.regs.pc = isr_addr;
-						
-						//println!("interrupt {} occured. jumping into ISR {:02x}", i, isr_addr);
-						return Some(20);
-					}					
-				}
-			}
-		}
-		None
+		self.regs.pc = if pending == 0 {
+			//nothing left to dispatch, the CPU ends up at 0000
+			0x0000
+		} else {
+			//the lowest bit has the highest priority
+			let i = pending.trailing_zeros();
+			//reset interrupt flag
+			let iflags = bus.untimed().peek8(IF_ADDR);
+			bus.untimed().write8(IF_ADDR, iflags & !(1<<i));
+			0x40 + 8*(i as u16)
+		};
+		bus.idle();
+		let bank = bus.untimed().code_bank(0x4000);
+		self.call_stack.call(Frame { kind : CallKind::Interrupt, site : pc, target : self.regs.pc, return_addr : pc,
+									 sp : self.regs.sp, bank : bank });
+		
+		Some(bus.cycles)
 	}
-}
\ No newline at end of file
+}
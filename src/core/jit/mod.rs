@@ -0,0 +1,541 @@
+//Translates hot blocks of straight-line ROM code into x86-64. Registers live in GBRegisters,
+//memory accesses and the passing of time go through callbacks into the same Memory and timing
+//the interpreter uses, so a translated block behaves exactly like interpreting it:
+//the system is advanced per access and the block is left as soon as an interrupt can be
+//dispatched or the system wants attention. Anything else is left to the interpreter.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs x86-64 Linux");
+
+mod x64;
+
+use std::io;
+use std::mem;
+
+use super::cpu::CPU;
+use super::memory::Memory;
+use super::icache::ICache;
+use super::instruction::*;
+use super::instruction::InstructionType::*;
+use super::operands::*;
+use super::operands::Operand::*;
+use super::interrupt::{IF_ADDR, IE_ADDR};
+use self::x64::{Asm, AluOp, Cond, CodeBuffer};
+
+const CODE_BUFFER_SIZE : usize = 16 << 20;
+//executions of a block start before it gets translated
+const HOT_THRESHOLD : u8 = 16;
+const MAX_BLOCK_INSNS : usize = 64;
+//`ld b,b` is used as a breakpoint by test ROMs, frontends look for it between instructions
+const OPCODE_LD_B_B : u8 = 0x40;
+
+//offsets into GBRegisters, which is repr(C) and little endian
+const OFF_F : u8 = 0;
+const OFF_SP : u8 = 8;
+const OFF_PC : u8 = 10;
+
+fn reg8_offset(r : Reg8Operand) -> u8 {
+	match r {
+		Reg8Operand::a => 1,
+		Reg8Operand::c => 2,
+		Reg8Operand::b => 3,
+		Reg8Operand::e => 4,
+		Reg8Operand::d => 5,
+		Reg8Operand::l => 6,
+		Reg8Operand::h => 7
+	}
+}
+
+fn reg16_offset(r : Reg16Operand) -> u8 {
+	match r {
+		Reg16Operand::af => 0,
+		Reg16Operand::bc => 2,
+		Reg16Operand::de => 4,
+		Reg16Operand::hl => 6,
+		Reg16Operand::sp => OFF_SP,
+		Reg16Operand::pc => OFF_PC
+	}
+}
+
+//what the callbacks get
+struct Context<M> {
+	sys : *mut M,
+	icache : *mut ICache,
+	ime : bool,
+	//a write went to the MBC, the code after it may be in another bank
+	mbc_written : bool,
+	cycles : u32
+}
+
+extern "C" fn jit_read8<M: Memory>(ctx : *mut Context<M>, addr : u32) -> u8 {
+	let ctx = unsafe { &mut *ctx };
+	let sys = unsafe { &mut *ctx.sys };
+	sys.update(4);
+	ctx.cycles += 4;
+	sys.read8(addr as u16)
+}
+
+extern "C" fn jit_write8<M: Memory>(ctx : *mut Context<M>, addr : u32, data : u32) {
+	let ctx = unsafe { &mut *ctx };
+	let sys = unsafe { &mut *ctx.sys };
+	sys.update(4);
+	ctx.cycles += 4;
+	sys.write8(addr as u16, data as u8);
+	unsafe { (*ctx.icache).invalidate(addr as u16) };
+	ctx.mbc_written |= addr < 0x8000;
+}
+
+extern "C" fn jit_advance<M: Memory>(ctx : *mut Context<M>, cycles : u32) {
+	let ctx = unsafe { &mut *ctx };
+	unsafe { (*ctx.sys).update(cycles) };
+	ctx.cycles += cycles;
+}
+
+//end of an instruction. Returns nonzero if the block has to be left.
+extern "C" fn jit_end<M: Memory>(ctx : *mut Context<M>, cycles : u32) -> u8 {
+	let ctx = unsafe { &mut *ctx };
+	let sys = unsafe { &mut *ctx.sys };
+	if cycles > 0 {
+		sys.update(cycles);
+		ctx.cycles += cycles;
+	}
+	let interrupt = ctx.ime && sys.peek8(IF_ADDR) & sys.peek8(IE_ADDR) & 0x1f != 0;
+	(interrupt || ctx.mbc_written || sys.yield_requested()) as u8
+}
+
+struct Callbacks {
+	read8 : usize,
+	write8 : usize,
+	advance : usize,
+	end : usize
+}
+
+pub struct Jit {
+	code : CodeBuffer,
+	//per address: the bank and the translated block, None if it can't be translated
+	blocks : Vec<Option<(u16, Option<usize>)>>,
+	heat : Vec<u8>,
+	//lahf result to Z, H and C
+	flag_table : Box<[u8; 256]>
+}
+
+impl Jit {
+
+	pub fn new() -> io::Result<Jit> {
+		let mut flag_table = Box::new([0; 256]);
+		for (ah, f) in flag_table.iter_mut().enumerate() {
+			let ah = ah as u8;
+			*f = ((ah & 0x40) << 1) | ((ah & 0x10) << 1) | ((ah & 0x01) << 4);
+		}
+		Ok(Jit {
+			code : try!(CodeBuffer::new(CODE_BUFFER_SIZE)),
+			blocks : vec![None; 0x8000],
+			heat : vec![0; 0x8000],
+			flag_table : flag_table
+		})
+	}
+
+	pub fn flush(&mut self) {
+		self.code.clear();
+		for b in self.blocks.iter_mut() {
+			*b = None;
+		}
+		for h in self.heat.iter_mut() {
+			*h = 0;
+		}
+	}
+
+	fn add_code(&mut self, code : &[u8]) -> io::Result<usize> {
+		match try!(self.code.add(code)) {
+			Some(addr) => Ok(addr),
+			None => {
+				//start over when the buffer is full
+				self.flush();
+				Ok(try!(self.code.add(code)).expect("block larger than the code buffer"))
+			}
+		}
+	}
+}
+
+impl<M: Memory> CPU<M> {
+
+	//falls back to the interpreter if there is no executable memory
+	pub fn enable_jit(&mut self) {
+		match Jit::new() {
+			Ok(jit) => self.jit = Some(Box::new(jit)),
+			Err(e) => println!("Couldn't start the JIT, interpreting everything: {}", e)
+		}
+	}
+
+	//for things that have to see every instruction, like breakpoints
+	pub fn disable_jit(&mut self) {
+		self.jit = None;
+	}
+
+	//run the translated block at PC if there is one or it just got hot. Returns the cycles it took.
+	pub fn run_compiled(&mut self) -> Option<u32> {
+		if self.jit.is_none() || self.halt_mode || self.halt_bug || self.ei_delay > 0 || self.trace_file.is_some() || self.history.is_some() {
+			return None
+		}
+		let pc = self.regs.pc;
+		//code in RAM may modify itself, leave it to the interpreter
+		if pc >= 0x8000 {
+			return None
+		}
+		let bank = match self.sys.code_bank(pc) {
+			Some(b) => b,
+			None => return None
+		};
+
+		let mut jit = self.jit.take().unwrap();
+		let block = match jit.blocks[pc as usize] {
+			Some((b, block)) if b == bank => block,
+			_ => {
+				let heat = &mut jit.heat[pc as usize];
+				*heat = heat.saturating_add(1);
+				if *heat < HOT_THRESHOLD {
+					None
+				} else {
+					*heat = 0;
+					let block = match self.translate(pc, MAX_BLOCK_INSNS).map(|code| jit.add_code(&code)) {
+						Some(Ok(addr)) => Some(addr),
+						Some(Err(e)) => {
+							//the JIT stays taken, so everything is interpreted from now on
+							println!("Turning the JIT off, couldn't add code: {}", e);
+							return None
+						},
+						None => None
+					};
+					jit.blocks[pc as usize] = Some((bank, block));
+					block
+				}
+			}
+		};
+		let flag_table = jit.flag_table.as_ptr();
+		self.jit = Some(jit);
+		block.map(|addr| self.call_block(addr, flag_table))
+	}
+
+	//translate and run just the instruction at PC, wherever it is. For checking the translator
+	//against the interpreter, returns None if the instruction isn't translated.
+	pub fn run_translated_insn(&mut self) -> Option<u32> {
+		let pc = self.regs.pc;
+		let code = match self.translate(pc, 1) {
+			Some(code) => code,
+			None => return None
+		};
+		let mut jit = self.jit.take().unwrap_or_else(|| Box::new(Jit::new().expect("couldn't start the JIT")));
+		let addr = jit.add_code(&code).expect("couldn't add code");
+		let flag_table = jit.flag_table.as_ptr();
+		self.jit = Some(jit);
+		Some(self.call_block(addr, flag_table))
+	}
+
+	fn call_block(&mut self, addr : usize, flag_table : *const u8) -> u32 {
+		let mut ctx = Context {
+			sys : &mut self.sys as *mut M,
+			icache : &mut self.icache as *mut ICache,
+			ime : self.regs.ime,
+			mbc_written : false,
+			cycles : 0
+		};
+		unsafe {
+			let block : extern "C" fn(*mut u8, *mut Context<M>, *const u8) = mem::transmute(addr);
+			block(&mut self.regs as *mut _ as *mut u8, &mut ctx, flag_table);
+		}
+		ctx.cycles
+	}
+
+	//machine code for up to `max_insns` instructions starting at `pc`, None if the first one
+	//can't be translated
+	fn translate(&mut self, pc : u16, max_insns : usize) -> Option<Vec<u8>> {
+		let callbacks = Callbacks {
+			read8 : jit_read8::<M> as *const () as usize,
+			write8 : jit_write8::<M> as *const () as usize,
+			advance : jit_advance::<M> as *const () as usize,
+			end : jit_end::<M> as *const () as usize
+		};
+		//stay within the ROM bank the block starts in
+		let region_end : u32 = if pc < 0x4000 { 0x4000 } else if pc < 0x8000 { 0x8000 } else { 0x10000 };
+
+		let mut asm = Asm::new();
+		asm.prologue();
+		let mut addr = pc;
+		let mut num_insns = 0;
+		while num_insns < max_insns {
+			let insn_bytes = self.fetch(addr);
+			let insn = self.decode(insn_bytes);
+			let next_pc = addr.wrapping_add(insn.length);
+			if addr as u32 + insn.length as u32 > region_end || insn_bytes[0] == OPCODE_LD_B_B {
+				break
+			}
+			let start = asm.code.len();
+			match emit_insn(&mut asm, &insn, next_pc, &callbacks) {
+				Some(true) => {
+					num_insns += 1;
+					addr = next_pc;
+				},
+				Some(false) => {
+					//a jump, the block ends here
+					num_insns += 1;
+					break
+				},
+				None => {
+					asm.truncate(start);
+					break
+				}
+			}
+		}
+		if num_insns == 0 {
+			return None
+		}
+		asm.epilogue();
+		Some(asm.code)
+	}
+}
+
+//emit one instruction. Returns Some(true) if the block may continue after it, Some(false)
+//after a jump and None if the instruction isn't supported.
+fn emit_insn(asm : &mut Asm, insn : &Instruction, next_pc : u16, cb : &Callbacks) -> Option<bool> {
+	//the fetch cycles of the instruction bytes come first, like on the Bus
+	let fetch_cycles = 4 * insn.length as u32;
+	match insn.itype {
+		nop => {
+			end_insn(asm, next_pc, fetch_cycles, cb);
+			Some(true)
+		},
+		ld => match (insn.dest, insn.src[0], insn.src[1]) {
+			(reg8(rd), reg8(rs), _) => {
+				asm.load8(reg8_offset(rs));
+				asm.store8(reg8_offset(rd));
+				end_insn(asm, next_pc, fetch_cycles, cb);
+				Some(true)
+			},
+			(reg8(rd), imm8(v), _) => {
+				asm.store8_imm(reg8_offset(rd), v);
+				end_insn(asm, next_pc, fetch_cycles, cb);
+				Some(true)
+			},
+			(reg8(rd), src, _) => {
+				emit_advance(asm, fetch_cycles, cb);
+				if !emit_address(asm, src) {
+					return None
+				}
+				asm.call(cb.read8);
+				asm.store8(reg8_offset(rd));
+				end_insn(asm, next_pc, 0, cb);
+				Some(true)
+			},
+			(reg16(rd), imm16(v), none) => {
+				asm.store16_imm(reg16_offset(rd), v);
+				end_insn(asm, next_pc, fetch_cycles, cb);
+				Some(true)
+			},
+			(reg16(Reg16Operand::sp), reg16(Reg16Operand::hl), none) => {
+				asm.load16(reg16_offset(Reg16Operand::hl));
+				asm.store16(OFF_SP);
+				end_insn(asm, next_pc, 8, cb);
+				Some(true)
+			},
+			(dest @ mem_reg(_), src, _) | (dest @ mem_imm(_), src, _) |
+			(dest @ mem_io_imm(_), src, _) | (dest @ mem_io_reg(_), src, _) => {
+				emit_advance(asm, fetch_cycles, cb);
+				match src {
+					reg8(rs) => asm.arg2_reg8(reg8_offset(rs)),
+					imm8(v) => asm.arg2_imm(v as u32),
+					_ => return None
+				}
+				if !emit_address(asm, dest) {
+					return None
+				}
+				asm.call(cb.write8);
+				end_insn(asm, next_pc, 0, cb);
+				Some(true)
+			},
+			_ => None
+		},
+		ldi | ldd => {
+			let hl = reg16_offset(Reg16Operand::hl);
+			let a = reg8_offset(Reg8Operand::a);
+			emit_advance(asm, fetch_cycles, cb);
+			asm.arg1_reg16(hl);
+			match insn.dest {
+				reg8(Reg8Operand::a) => {
+					asm.call(cb.read8);
+					asm.store8(a);
+				},
+				_ => {
+					asm.arg2_reg8(a);
+					asm.call(cb.write8);
+				}
+			}
+			asm.inc16(hl, insn.itype == ldd);
+			end_insn(asm, next_pc, 0, cb);
+			Some(true)
+		},
+		add | adc | sub | sbc | cp | and | xor | or => {
+			match insn.dest {
+				reg8(Reg8Operand::a) | none => {},
+				_ => return None
+			}
+			//operand to ecx
+			let mut remaining = fetch_cycles;
+			match insn.src[0] {
+				reg8(rs) => asm.load8_ecx(reg8_offset(rs)),
+				imm8(v) => asm.mov_ecx_imm(v as u32),
+				mem_reg(Reg16Operand::hl) => {
+					emit_advance(asm, fetch_cycles, cb);
+					asm.arg1_reg16(reg16_offset(Reg16Operand::hl));
+					asm.call(cb.read8);
+					asm.mov_ecx_eax();
+					remaining = 0;
+				},
+				_ => return None
+			}
+			let a = reg8_offset(Reg8Operand::a);
+			asm.load8(a);
+			let (op, n) = match insn.itype {
+				add => (AluOp::Add, false),
+				adc => (AluOp::Adc, false),
+				sub => (AluOp::Sub, true),
+				sbc => (AluOp::Sbb, true),
+				cp => (AluOp::Cmp, true),
+				and => (AluOp::And, false),
+				xor => (AluOp::Xor, false),
+				_ => (AluOp::Or, false)
+			};
+			if insn.itype == adc || insn.itype == sbc {
+				//carry in
+				asm.bt(OFF_F, 4);
+			}
+			asm.alu(op);
+			asm.flags_to_dl();
+			if insn.itype != cp {
+				asm.store8(a);
+			}
+			match insn.itype {
+				and => { asm.and_dl(0x80); asm.or_dl(0x20); },
+				xor | or => asm.and_dl(0x80),
+				_ => if n { asm.or_dl(0x40) }
+			}
+			asm.store8_dl(OFF_F);
+			end_insn(asm, next_pc, remaining, cb);
+			Some(true)
+		},
+		inc | dec => {
+			let is_dec = insn.itype == dec;
+			match insn.dest {
+				reg8(rd) => {
+					asm.load8(reg8_offset(rd));
+					asm.inc_al(is_dec);
+					asm.store8(reg8_offset(rd));
+					emit_inc_flags(asm, is_dec);
+					end_insn(asm, next_pc, fetch_cycles, cb);
+				},
+				mem_reg(Reg16Operand::hl) => {
+					let hl = reg16_offset(Reg16Operand::hl);
+					emit_advance(asm, fetch_cycles, cb);
+					asm.arg1_reg16(hl);
+					asm.call(cb.read8);
+					asm.inc_al(is_dec);
+					emit_inc_flags(asm, is_dec);
+					asm.arg2_al();
+					asm.arg1_reg16(hl);
+					asm.call(cb.write8);
+					end_insn(asm, next_pc, 0, cb);
+				},
+				reg16(rd) => {
+					asm.inc16(reg16_offset(rd), is_dec);
+					end_insn(asm, next_pc, 8, cb);
+				},
+				_ => return None
+			}
+			Some(true)
+		},
+		jp | jr => {
+			let (taken_cycles, not_taken_cycles) = match (insn.itype, insn.src[0]) {
+				(jp, reg16(Reg16Operand::hl)) => (4, 4),
+				(jp, imm16(_)) => (16, 12),
+				(jr, imm8(_)) => (12, 8),
+				_ => return None
+			};
+			if insn.cc != CCOperand::none {
+				let (mask, cond) = match insn.cc {
+					CCOperand::z => (0x80, Cond::Zero),
+					CCOperand::nz => (0x80, Cond::NotZero),
+					CCOperand::c => (0x10, Cond::Zero),
+					_ => (0x10, Cond::NotZero)
+				};
+				//skip the jump if the tested flag is clear (z, c) or set (nz, nc)
+				let skip = asm.branch_on_bits(OFF_F, mask, cond);
+				emit_jump_target(asm, insn, next_pc);
+				emit_end(asm, taken_cycles, cb);
+				asm.exit();
+				asm.bind(skip);
+				asm.store16_imm(OFF_PC, next_pc);
+				emit_end(asm, not_taken_cycles, cb);
+			} else {
+				emit_jump_target(asm, insn, next_pc);
+				emit_end(asm, taken_cycles, cb);
+			}
+			Some(false)
+		},
+		_ => None
+	}
+}
+
+//esi = the address of a memory operand
+fn emit_address(asm : &mut Asm, op : Operand) -> bool {
+	match op {
+		mem_reg(r) => asm.arg1_reg16(reg16_offset(r)),
+		mem_imm(addr) => asm.arg1_imm(addr as u32),
+		mem_io_imm(o) => asm.arg1_imm(0xff00 + o as u32),
+		mem_io_reg(r) => asm.arg1_io_reg8(reg8_offset(r)),
+		_ => return false
+	}
+	true
+}
+
+fn emit_jump_target(asm : &mut Asm, insn : &Instruction, next_pc : u16) {
+	match insn.src[0] {
+		imm16(addr) => asm.store16_imm(OFF_PC, addr),
+		imm8(off) => asm.store16_imm(OFF_PC, next_pc.wrapping_add(off as i8 as u16)),
+		_ => {
+			asm.load16(reg16_offset(Reg16Operand::hl));
+			asm.store16(OFF_PC);
+		}
+	}
+}
+
+//inc/dec leave C alone. Expects the x86 flags of the operation.
+fn emit_inc_flags(asm : &mut Asm, is_dec : bool) {
+	asm.flags_to_dl();
+	asm.and_dl(0xa0);
+	asm.load8_ecx(OFF_F);
+	asm.and_cl(0x10);
+	asm.or_dl_cl();
+	if is_dec {
+		asm.or_dl(0x40);
+	}
+	asm.store8_dl(OFF_F);
+}
+
+//the cycles before the first memory access of an instruction. Callbacks clobber the
+//argument registers, so this comes before setting up the access.
+fn emit_advance(asm : &mut Asm, cycles : u32, cb : &Callbacks) {
+	asm.arg1_imm(cycles);
+	asm.call(cb.advance);
+}
+
+//let the rest of the instruction's cycles pass, al = whether to leave the block
+fn emit_end(asm : &mut Asm, cycles : u32, cb : &Callbacks) {
+	asm.arg1_imm(cycles);
+	asm.call(cb.end);
+}
+
+//update PC, finish the instruction and leave if needed
+fn end_insn(asm : &mut Asm, next_pc : u16, cycles : u32, cb : &Callbacks) {
+	asm.store16_imm(OFF_PC, next_pc);
+	emit_end(asm, cycles, cb);
+	asm.exit_if_al();
+}
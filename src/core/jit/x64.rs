@@ -0,0 +1,327 @@
+extern crate libc;
+
+use std::io;
+use std::ptr;
+
+//just the x86-64 instructions the translator needs. Generated code keeps
+//rbx = &mut GBRegisters, r12 = callback context, r13 = flag conversion table.
+
+#[derive(Copy,Clone)]
+pub enum AluOp {
+	Add,
+	Adc,
+	Sub,
+	Sbb,
+	And,
+	Xor,
+	Or,
+	Cmp
+}
+
+#[derive(Copy,Clone)]
+pub enum Cond {
+	Zero,
+	NotZero
+}
+
+pub struct Asm {
+	pub code : Vec<u8>,
+	//positions of rel32 jumps to the epilogue
+	exits : Vec<usize>
+}
+
+impl Asm {
+
+	pub fn new() -> Asm {
+		Asm {
+			code : Vec::with_capacity(1024),
+			exits : Vec::new()
+		}
+	}
+
+	fn emit(&mut self, bytes : &[u8]) {
+		self.code.extend_from_slice(bytes);
+	}
+
+	fn emit32(&mut self, v : u32) {
+		self.emit(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+	}
+
+	//fn(regs, ctx, flag_table)
+	pub fn prologue(&mut self) {
+		self.emit(&[0x53]);					//push rbx
+		self.emit(&[0x41, 0x54]);			//push r12
+		self.emit(&[0x41, 0x55]);			//push r13
+		self.emit(&[0x48, 0x89, 0xfb]);		//mov rbx, rdi
+		self.emit(&[0x49, 0x89, 0xf4]);		//mov r12, rsi
+		self.emit(&[0x49, 0x89, 0xd5]);		//mov r13, rdx
+	}
+
+	//resolves all exits, nothing may be emitted afterwards
+	pub fn epilogue(&mut self) {
+		let here = self.code.len();
+		for &pos in &self.exits {
+			let rel = (here - (pos + 4)) as u32;
+			self.code[pos..pos + 4].copy_from_slice(&[rel as u8, (rel >> 8) as u8, (rel >> 16) as u8, (rel >> 24) as u8]);
+		}
+		self.emit(&[0x41, 0x5d]);			//pop r13
+		self.emit(&[0x41, 0x5c]);			//pop r12
+		self.emit(&[0x5b]);					//pop rbx
+		self.emit(&[0xc3]);					//ret
+	}
+
+	//drop everything emitted from `pos` on
+	pub fn truncate(&mut self, pos : usize) {
+		self.code.truncate(pos);
+		self.exits.retain(|&e| e < pos);
+	}
+
+	//jmp epilogue
+	pub fn exit(&mut self) {
+		self.emit(&[0xe9]);
+		let pos = self.code.len();
+		self.exits.push(pos);
+		self.emit32(0);
+	}
+
+	//jump to the epilogue if al != 0
+	pub fn exit_if_al(&mut self) {
+		self.emit(&[0x84, 0xc0]);			//test al, al
+		self.emit(&[0x0f, 0x85]);			//jnz rel32
+		let pos = self.code.len();
+		self.exits.push(pos);
+		self.emit32(0);
+	}
+
+	//test byte [rbx+off], mask; jcc rel32. Returns the position to patch with bind().
+	pub fn branch_on_bits(&mut self, off : u8, mask : u8, cond : Cond) -> usize {
+		self.emit(&[0xf6, 0x43, off, mask]);
+		self.emit(&[0x0f, match cond { Cond::Zero => 0x84, Cond::NotZero => 0x85 }]);
+		let pos = self.code.len();
+		self.emit32(0);
+		pos
+	}
+
+	//let a branch from branch_on_bits() jump here
+	pub fn bind(&mut self, pos : usize) {
+		let rel = (self.code.len() - (pos + 4)) as u32;
+		self.code[pos..pos + 4].copy_from_slice(&[rel as u8, (rel >> 8) as u8, (rel >> 16) as u8, (rel >> 24) as u8]);
+	}
+
+	//movzx eax, byte [rbx+off]
+	pub fn load8(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb6, 0x43, off]);
+	}
+
+	//movzx ecx, byte [rbx+off]
+	pub fn load8_ecx(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb6, 0x4b, off]);
+	}
+
+	//mov [rbx+off], al
+	pub fn store8(&mut self, off : u8) {
+		self.emit(&[0x88, 0x43, off]);
+	}
+
+	//mov byte [rbx+off], imm8
+	pub fn store8_imm(&mut self, off : u8, v : u8) {
+		self.emit(&[0xc6, 0x43, off, v]);
+	}
+
+	//movzx eax, word [rbx+off]
+	pub fn load16(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb7, 0x43, off]);
+	}
+
+	//mov [rbx+off], ax
+	pub fn store16(&mut self, off : u8) {
+		self.emit(&[0x66, 0x89, 0x43, off]);
+	}
+
+	//mov word [rbx+off], imm16
+	pub fn store16_imm(&mut self, off : u8, v : u16) {
+		self.emit(&[0x66, 0xc7, 0x43, off, v as u8, (v >> 8) as u8]);
+	}
+
+	//inc/dec word [rbx+off]
+	pub fn inc16(&mut self, off : u8, dec : bool) {
+		self.emit(&[0x66, 0xff, if dec { 0x4b } else { 0x43 }, off]);
+	}
+
+	//inc/dec al
+	pub fn inc_al(&mut self, dec : bool) {
+		self.emit(&[0xfe, if dec { 0xc8 } else { 0xc0 }]);
+	}
+
+	//mov ecx, imm32
+	pub fn mov_ecx_imm(&mut self, v : u32) {
+		self.emit(&[0xb9]);
+		self.emit32(v);
+	}
+
+	//mov ecx, eax
+	pub fn mov_ecx_eax(&mut self) {
+		self.emit(&[0x89, 0xc1]);
+	}
+
+	//bt dword [rbx+off], bit: sets CF
+	pub fn bt(&mut self, off : u8, bit : u8) {
+		self.emit(&[0x0f, 0xba, 0x63, off, bit]);
+	}
+
+	//op al, cl
+	pub fn alu(&mut self, op : AluOp) {
+		let opcode = match op {
+			AluOp::Add => 0x00,
+			AluOp::Or => 0x08,
+			AluOp::Adc => 0x10,
+			AluOp::Sbb => 0x18,
+			AluOp::And => 0x20,
+			AluOp::Sub => 0x28,
+			AluOp::Xor => 0x30,
+			AluOp::Cmp => 0x38
+		};
+		self.emit(&[opcode, 0xc8]);
+	}
+
+	//lahf, then dl = table[ah]
+	pub fn flags_to_dl(&mut self) {
+		self.emit(&[0x9f]);							//lahf
+		self.emit(&[0x0f, 0xb6, 0xd4]);				//movzx edx, ah
+		self.emit(&[0x41, 0x8a, 0x54, 0x15, 0x00]);	//mov dl, [r13+rdx]
+	}
+
+	//and dl, imm8
+	pub fn and_dl(&mut self, v : u8) {
+		self.emit(&[0x80, 0xe2, v]);
+	}
+
+	//or dl, imm8
+	pub fn or_dl(&mut self, v : u8) {
+		self.emit(&[0x80, 0xca, v]);
+	}
+
+	//and cl, imm8
+	pub fn and_cl(&mut self, v : u8) {
+		self.emit(&[0x80, 0xe1, v]);
+	}
+
+	//or dl, cl
+	pub fn or_dl_cl(&mut self) {
+		self.emit(&[0x08, 0xca]);
+	}
+
+	//mov [rbx+off], dl
+	pub fn store8_dl(&mut self, off : u8) {
+		self.emit(&[0x88, 0x53, off]);
+	}
+
+	//first argument: the callback context
+	fn arg_ctx(&mut self) {
+		self.emit(&[0x4c, 0x89, 0xe7]);				//mov rdi, r12
+	}
+
+	//mov esi, imm32
+	pub fn arg1_imm(&mut self, v : u32) {
+		self.emit(&[0xbe]);
+		self.emit32(v);
+	}
+
+	//movzx esi, word [rbx+off]
+	pub fn arg1_reg16(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb7, 0x73, off]);
+	}
+
+	//movzx esi, byte [rbx+off]; or esi, 0xff00
+	pub fn arg1_io_reg8(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb6, 0x73, off]);
+		self.emit(&[0x81, 0xce, 0x00, 0xff, 0x00, 0x00]);
+	}
+
+	//mov edx, imm32
+	pub fn arg2_imm(&mut self, v : u32) {
+		self.emit(&[0xba]);
+		self.emit32(v);
+	}
+
+	//movzx edx, byte [rbx+off]
+	pub fn arg2_reg8(&mut self, off : u8) {
+		self.emit(&[0x0f, 0xb6, 0x53, off]);
+	}
+
+	//movzx edx, al
+	pub fn arg2_al(&mut self) {
+		self.emit(&[0x0f, 0xb6, 0xd0]);
+	}
+
+	//call a callback taking the context and the arguments set up before
+	pub fn call(&mut self, f : usize) {
+		self.arg_ctx();
+		self.emit(&[0x48, 0xb8]);					//mov rax, imm64
+		self.emit32(f as u32);
+		self.emit32((f as u64 >> 32) as u32);
+		self.emit(&[0xff, 0xd0]);					//call rax
+	}
+}
+
+//executable memory for translated blocks. It is only writable while code is added.
+pub struct CodeBuffer {
+	base : *mut u8,
+	size : usize,
+	used : usize
+}
+
+impl CodeBuffer {
+
+	pub fn new(size : usize) -> io::Result<CodeBuffer> {
+		let base = unsafe {
+			libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+		};
+		if base == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error())
+		}
+		let buffer = CodeBuffer {
+			base : base as *mut u8,
+			size : size,
+			used : 0
+		};
+		try!(buffer.protect(libc::PROT_READ | libc::PROT_EXEC));
+		Ok(buffer)
+	}
+
+	fn protect(&self, prot : libc::c_int) -> io::Result<()> {
+		if unsafe { libc::mprotect(self.base as *mut libc::c_void, self.size, prot) } != 0 {
+			return Err(io::Error::last_os_error())
+		}
+		Ok(())
+	}
+
+	//copy code into the buffer and return its address, None if the buffer is full
+	pub fn add(&mut self, code : &[u8]) -> io::Result<Option<usize>> {
+		//keep blocks 16 byte aligned
+		let start = (self.used + 15) & !15;
+		if start + code.len() > self.size {
+			return Ok(None)
+		}
+		try!(self.protect(libc::PROT_READ | libc::PROT_WRITE));
+		unsafe {
+			ptr::copy_nonoverlapping(code.as_ptr(), self.base.offset(start as isize), code.len());
+		}
+		try!(self.protect(libc::PROT_READ | libc::PROT_EXEC));
+		self.used = start + code.len();
+		Ok(Some(self.base as usize + start))
+	}
+
+	pub fn clear(&mut self) {
+		self.used = 0;
+	}
+}
+
+impl Drop for CodeBuffer {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.base as *mut libc::c_void, self.size);
+		}
+	}
+}
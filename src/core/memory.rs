@@ -4,15 +4,97 @@ pub trait Memory {
     
     fn write8(&mut self, addr: u16, data: u8);
     
+    //read for the CPU's own purposes: instruction fetches and the interrupt lines.
+    //Unlike read8 it doesn't count as a data access, e.g. for watchpoints.
+    fn peek8(&mut self, addr: u16) -> u8 {
+    	self.read8(addr)
+    }
+    
+    //low byte first, like the CPU reads it
     fn read16(&mut self, addr: u16) -> u16 {
-    	((self.read8(addr+1) as u16) << 8) | (self.read8(addr) as u16)
+    	let low = self.read8(addr) as u16;
+    	low | ((self.read8(addr.wrapping_add(1)) as u16) << 8)
     }
     
     fn write16(&mut self, addr: u16, data: u16) {
     	self.write8(addr, data as u8);
-    	self.write8(addr+1, (data >> 8) as u8)
+    	self.write8(addr.wrapping_add(1), (data >> 8) as u8)
+    }
+    
+    //advance the attached hardware by `delta` clock cycles
+    fn update(&mut self, _delta: u32) {
+    }
+    
+    //called by STOP. Performs a prepared CGB speed switch and returns the new speed
+    //(true for double speed), or None if no switch was requested.
+    fn switch_speed(&mut self) -> Option<bool> {
+    	None
+    }
+    
+    //called by STOP, which clears the divider without a bus write
+    fn reset_divider(&mut self) {
     }
     
+    //the bank mapped at a code address. None if the code there may change other than by
+    //CPU writes, instructions at such addresses are decoded every time.
+    fn code_bank(&self, _addr: u16) -> Option<u16> {
+    	None
+    }
+    
+    //true if whoever runs the CPU wants control back as soon as possible, e.g. at the end of
+    //a frame. Translated code checks this, the interpreter returns after every instruction anyway.
+    fn yield_requested(&self) -> bool {
+    	false
+    }
+    
+}
+
+//a read or write with the clock cycles elapsed when it happened
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Access {
+    pub cycles: u32,
+    pub addr: u16,
+    pub data: u8,
+    pub write: bool
+}
+
+//plain 64K of RAM without any hardware behind it. Keeps a log of the accesses to check
+//when an instruction does them.
+pub struct FlatRam {
+    ram: Box<[u8; 0x10000]>,
+    cycles: u32,
+    pub accesses: Vec<Access>
+}
+
+impl FlatRam {
+    pub fn new() -> FlatRam {
+        FlatRam {
+            ram: Box::new([0; 0x10000]),
+            cycles: 0,
+            accesses: Vec::new()
+        }
+    }
+}
+
+impl Memory for FlatRam {
+    fn read8(&mut self, addr: u16) -> u8 {
+        let data = self.ram[addr as usize];
+        self.accesses.push(Access { cycles: self.cycles, addr: addr, data: data, write: false });
+        data
+    }
+
+    fn write8(&mut self, addr: u16, data: u8) {
+        self.accesses.push(Access { cycles: self.cycles, addr: addr, data: data, write: true });
+        self.ram[addr as usize] = data
+    }
+
+    fn peek8(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn update(&mut self, delta: u32) {
+        self.cycles += delta;
+    }
 }
 
 pub struct MemoryDummy;
@@ -1,9 +1,17 @@
 pub mod register;
-mod gb;
+pub mod gb;
 pub mod memory;
+mod bus;
+pub mod icache;
+pub mod callstack;
+pub mod history;
 pub mod cpu;
 pub mod instruction;
 pub mod operands;
 mod decode;
-mod execute;
-mod interrupt;
\ No newline at end of file
+pub mod execute;
+mod interrupt;
+#[cfg(test)]
+mod sm83test;
+#[cfg(feature = "jit")]
+pub mod jit;
\ No newline at end of file
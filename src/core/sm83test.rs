@@ -0,0 +1,257 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use core::cpu::CPU;
+use core::memory::{Memory, FlatRam, Access};
+use core::operands::Reg8Operand;
+use serde_json::{self, Value};
+
+const REGISTERS : [(Reg8Operand, &'static str); 6] = [
+	(Reg8Operand::b, "b"), (Reg8Operand::c, "c"), (Reg8Operand::d, "d"),
+	(Reg8Operand::e, "e"), (Reg8Operand::h, "h"), (Reg8Operand::l, "l")
+];
+
+//opcodes without an instruction. 0xcb is the prefix and has its own files.
+const UNUSED_OPCODES : [u8; 12] = [0xcb, 0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd];
+
+//a few vectors in the format of https://github.com/SingleStepTests/sm83, checked in with the sources
+#[test]
+fn sm83_vectors() {
+	let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/sm83");
+	assert!(run_directory(dir, false).unwrap());
+}
+
+//the whole suite, from a checkout of the repository above. Run it with
+//SM83_TESTS=path/to/sm83/v1 cargo test -- --ignored
+#[test]
+#[ignore]
+fn sm83_full_suite() {
+	let dir = env::var("SM83_TESTS").expect("SM83_TESTS isn't set");
+	assert!(run_directory(&dir, true).unwrap());
+}
+
+//runs the single step test vectors against the CPU core. `dir` contains one file per opcode,
+//named "xx.json" or "cb xx.json". Missing files only fail the run if `require_all` is set.
+fn run_directory(dir : &str, require_all : bool) -> io::Result<bool> {
+	let mut files = Vec::new();
+	for op in 0..256 {
+		if !UNUSED_OPCODES.contains(&(op as u8)) {
+			files.push(format!("{:02x}.json", op));
+		}
+	}
+	for op in 0..256 {
+		files.push(format!("cb {:02x}.json", op));
+	}
+
+	let (mut passed, mut failed, mut missing) = (0, 0, 0);
+	for name in &files {
+		let path = Path::new(dir).join(name);
+		if !path.exists() {
+			if require_all {
+				println!("{:<12} missing", name);
+				missing += 1;
+			}
+			continue
+		}
+		let tests = try!(load_tests(&path));
+		let vectors = match tests.as_array() {
+			Some(v) => v,
+			None => return Err(invalid_data(&format!("{}: expected an array of tests", name)))
+		};
+
+		let mut first_failure = None;
+		let mut num_failed = 0;
+		for test in vectors {
+			if let Err(e) = check_vector(test) {
+				num_failed += 1;
+				if first_failure.is_none() {
+					let test_name = test.get("name").and_then(|n| n.as_str()).unwrap_or("?");
+					first_failure = Some(format!("{}: {}", test_name, e));
+				}
+			}
+		}
+
+		if let Some(msg) = first_failure {
+			println!("{:<12} FAIL {}/{} ({})", name, num_failed, vectors.len(), msg);
+			failed += 1;
+		} else {
+			passed += 1;
+		}
+	}
+
+	println!("{} opcodes passed, {} failed, {} missing", passed, failed, missing);
+	Ok(failed == 0 && missing == 0)
+}
+
+fn load_tests(path : &Path) -> io::Result<Value> {
+	let mut text = String::new();
+	try!(try!(File::open(path)).read_to_string(&mut text));
+	serde_json::from_str(&text).map_err(|e| invalid_data(&format!("{}: {}", path.display(), e)))
+}
+
+fn invalid_data(msg : &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn field(state : &Value, name : &str) -> Result<u64, String> {
+	state.get(name).and_then(|v| v.as_u64()).ok_or(format!("missing field {}", name))
+}
+
+//[[address, value], ...]
+fn ram_entries(state : &Value) -> Result<Vec<(u16, u8)>, String> {
+	let mut entries = Vec::new();
+	for entry in state.get("ram").and_then(|r| r.as_array()).map_or(&[][..], |r| &r[..]) {
+		match (entry.as_array().and_then(|e| e.get(0)).and_then(|a| a.as_u64()),
+			   entry.as_array().and_then(|e| e.get(1)).and_then(|v| v.as_u64())) {
+			(Some(addr), Some(value)) => entries.push((addr as u16, value as u8)),
+			_ => return Err("invalid ram entry".to_string())
+		}
+	}
+	Ok(entries)
+}
+
+#[cfg(not(feature = "jit"))]
+fn check_vector(test : &Value) -> Result<(), String> {
+	run_vector(test, interpret)
+}
+
+//the translated instruction has to match the vector as well
+#[cfg(feature = "jit")]
+fn check_vector(test : &Value) -> Result<(), String> {
+	try!(run_vector(test, interpret));
+	run_vector(test, translate).map_err(|e| format!("jit: {}", e))
+}
+
+fn interpret(cpu : &mut CPU<FlatRam>) -> Result<Option<u32>, String> {
+	let pc = cpu.regs.pc;
+	let insn_bytes = cpu.fetch(pc);
+	let insn = cpu.decode(insn_bytes);
+	cpu.execute(insn).map(Some).map_err(|e| format!("execution error {:?}", e))
+}
+
+//None if the instruction isn't translated
+#[cfg(feature = "jit")]
+fn translate(cpu : &mut CPU<FlatRam>) -> Result<Option<u32>, String> {
+	Ok(cpu.run_translated_insn())
+}
+
+//set up the initial state, run one instruction with `step` and compare
+fn run_vector(test : &Value, step : fn(&mut CPU<FlatRam>) -> Result<Option<u32>, String>) -> Result<(), String> {
+	let initial = try!(test.get("initial").ok_or("missing initial state"));
+	let expected = try!(test.get("final").ok_or("missing final state"));
+	let expected_bus = try!(test.get("cycles").and_then(|c| c.as_array()).ok_or("missing cycles"));
+	let bus_cycles = expected_bus.len();
+
+	let mut cpu = CPU::new(FlatRam::new());
+	cpu.regs.pc = try!(field(initial, "pc")) as u16;
+	cpu.regs.sp = try!(field(initial, "sp")) as u16;
+	cpu.regs.af = ((try!(field(initial, "a")) << 8) | try!(field(initial, "f"))) as u16;
+	for &(reg, name) in &REGISTERS {
+		cpu.regs.set8(reg, try!(field(initial, name)) as u8);
+	}
+	cpu.regs.ime = try!(field(initial, "ime")) != 0;
+	for (addr, value) in try!(ram_entries(initial)) {
+		cpu.sys.write8(addr, value);
+	}
+	cpu.sys.accesses.clear();
+
+	let pc = cpu.regs.pc;
+	let insn_bytes = cpu.fetch(pc);
+	let insn_length = cpu.decode(insn_bytes).length as usize;
+	let cycles = match try!(step(&mut cpu)) {
+		Some(c) => c,
+		None => return Ok(())
+	};
+	let bus = try!(bus_log(&cpu.sys.accesses, pc, &insn_bytes[..insn_length], cycles));
+
+	let mut errors = Vec::new();
+	{
+		let mut check = |name : &str, actual : u64| {
+			match field(expected, name) {
+				Ok(value) if value != actual => errors.push(format!("{}={:02x} expected {:02x}", name, actual, value)),
+				Ok(_) => {},
+				Err(e) => errors.push(e)
+			}
+		};
+		check("pc", cpu.regs.pc as u64);
+		check("sp", cpu.regs.sp as u64);
+		check("a", cpu.regs.get8(Reg8Operand::a) as u64);
+		check("f", (cpu.regs.af & 0xff) as u64);
+		for &(reg, name) in &REGISTERS {
+			check(name, cpu.regs.get8(reg) as u64);
+		}
+		//the vectors don't model the EI delay, a pending EI counts as enabled
+		check("ime", (cpu.regs.ime || cpu.ei_delay > 0) as u64);
+	}
+	for (addr, value) in try!(ram_entries(expected)) {
+		let actual = cpu.sys.read8(addr);
+		if actual != value {
+			errors.push(format!("({:04x})={:02x} expected {:02x}", addr, actual, value));
+		}
+	}
+	if cycles as usize != 4*bus_cycles {
+		errors.push(format!("{} cycles expected {}", cycles, 4*bus_cycles));
+	}
+	for (i, (actual, entry)) in bus.iter().zip(expected_bus).enumerate() {
+		let expected = try!(bus_entry(entry));
+		if *actual != expected {
+			errors.push(format!("M-cycle {}: {} expected {}", i, describe(actual), describe(&expected)));
+		}
+	}
+
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(errors.join(", "))
+	}
+}
+
+//what the bus does in an M-cycle: address, data and true for a write, None if idle
+type BusCycle = Option<(u16, u8, bool)>;
+
+//one entry of the "cycles" array: [address, data, "r-m"], [address, data, "-wm"] or null
+fn bus_entry(entry : &Value) -> Result<BusCycle, String> {
+	if entry.is_null() {
+		return Ok(None)
+	}
+	let fields = try!(entry.as_array().ok_or("invalid cycle entry"));
+	match (fields.get(0).and_then(|a| a.as_u64()), fields.get(1).and_then(|d| d.as_u64()), fields.get(2).and_then(|k| k.as_str())) {
+		(Some(addr), Some(data), Some(kind)) if kind.starts_with('r') => Ok(Some((addr as u16, data as u8, false))),
+		(Some(addr), Some(data), Some(kind)) if kind.as_bytes().get(1) == Some(&b'w') => Ok(Some((addr as u16, data as u8, true))),
+		(_, _, Some(_)) => Ok(None),
+		_ => Err("invalid cycle entry".to_string())
+	}
+}
+
+//the accesses of an instruction taking `cycles`, one entry per M-cycle. The opcode and operand
+//bytes are fetched in the first M-cycles, without going through the logged accesses.
+fn bus_log(accesses : &[Access], pc : u16, insn_bytes : &[u8], cycles : u32) -> Result<Vec<BusCycle>, String> {
+	let mut log = vec![None; cycles as usize / 4];
+	for (i, &byte) in insn_bytes.iter().enumerate() {
+		if i < log.len() {
+			log[i] = Some((pc.wrapping_add(i as u16), byte, false));
+		}
+	}
+	for a in accesses {
+		//the bus advances the system by an M-cycle before each access
+		if a.cycles == 0 || a.cycles % 4 != 0 || a.cycles > cycles {
+			return Err(format!("access to {:04x} at cycle {}", a.addr, a.cycles))
+		}
+		let m_cycle = (a.cycles / 4 - 1) as usize;
+		if log[m_cycle].is_some() {
+			return Err(format!("two accesses in M-cycle {}", m_cycle))
+		}
+		log[m_cycle] = Some((a.addr, a.data, a.write));
+	}
+	Ok(log)
+}
+
+fn describe(cycle : &BusCycle) -> String {
+	match *cycle {
+		Some((addr, data, false)) => format!("read {:02x} from {:04x}", data, addr),
+		Some((addr, data, true)) => format!("write {:02x} to {:04x}", data, addr),
+		None => "idle".to_string()
+	}
+}
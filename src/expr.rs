@@ -0,0 +1,416 @@
+use std::str;
+
+use core::cpu::CPU;
+use core::gb::{CARRY_FLAG, HALFCARRY_FLAG, SUB_FLAG, ZERO_FLAG};
+use core::operands::{Reg8Operand, Reg16Operand};
+use address::BankAddr;
+use symbols::Symbols;
+
+//expressions for the debugger prompt. Numbers are hex like everywhere else in the prompt,
+//names of registers and variables take precedence, then labels, so "a" is a register and
+//"0a" a number. [addr] reads memory, [bank:addr] a specific ROM bank and [label] the bank
+//of the label.
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Var {
+	Reg8(Reg8Operand),
+	Reg16(Reg16Operand),
+	F,
+	//flag bit in F
+	Flag(i32),
+	Ime,
+	//the ROM bank mapped at 4000-7fff
+	Bank,
+	Ly
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum UnaryOp {
+	Neg,
+	Not,
+	Complement
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum BinaryOp {
+	Mul, Div, Rem,
+	Add, Sub,
+	Shl, Shr,
+	Lt, Le, Gt, Ge,
+	Eq, Ne,
+	And,
+	Xor,
+	Or,
+	LogicalAnd,
+	LogicalOr
+}
+
+#[derive(Debug,Clone)]
+pub enum Expr {
+	Number(i64),
+	Var(Var),
+	//the address of a label
+	Label(BankAddr),
+	//a byte of memory, optionally in a ROM bank
+	Memory(Option<Box<Expr>>, Box<Expr>),
+	Unary(UnaryOp, Box<Expr>),
+	Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+impl Expr {
+
+	//memory is read without triggering watchpoints
+	pub fn eval(&self, cpu : &mut CPU) -> Result<i64, String> {
+		match *self {
+			Expr::Number(n) => Ok(n),
+			Expr::Var(v) => Ok(match v {
+				Var::Reg8(r) => cpu.regs.get8(r) as i64,
+				Var::Reg16(r) => cpu.regs.get16(r) as i64,
+				Var::F => (cpu.regs.af & 0xff) as i64,
+				Var::Flag(bit) => ((cpu.regs.af >> bit) & 1) as i64,
+				Var::Ime => cpu.regs.ime as i64,
+				Var::Bank => cpu.sys.mbc.rom_bank() as i64,
+				Var::Ly => cpu.sys.peek8(0xff44) as i64
+			}),
+			Expr::Label(l) => Ok(l.addr as i64),
+			Expr::Memory(ref bank, ref addr) => {
+				let bank = match (bank, &**addr) {
+					(&Some(ref b), _) => Some(try!(b.eval(cpu)) as u16),
+					(&None, &Expr::Label(l)) => l.bank,
+					(&None, _) => None
+				};
+				let addr = try!(BankAddr::with_bank(bank, try!(addr.eval(cpu)) as u16));
+				Ok(addr.read(&mut cpu.sys) as i64)
+			},
+			Expr::Unary(op, ref e) => {
+				let v = try!(e.eval(cpu));
+				Ok(match op {
+					UnaryOp::Neg => v.wrapping_neg(),
+					UnaryOp::Not => (v == 0) as i64,
+					UnaryOp::Complement => !v
+				})
+			},
+			Expr::Binary(op, ref l, ref r) => {
+				let l = try!(l.eval(cpu));
+				//both sides are side effect free, but don't evaluate what doesn't matter
+				match op {
+					BinaryOp::LogicalAnd if l == 0 => return Ok(0),
+					BinaryOp::LogicalOr if l != 0 => return Ok(1),
+					_ => {}
+				}
+				let r = try!(r.eval(cpu));
+				Ok(match op {
+					BinaryOp::Mul => l.wrapping_mul(r),
+					BinaryOp::Div | BinaryOp::Rem if r == 0 => return Err("division by zero".to_string()),
+					BinaryOp::Div => l.wrapping_div(r),
+					BinaryOp::Rem => l.wrapping_rem(r),
+					BinaryOp::Add => l.wrapping_add(r),
+					BinaryOp::Sub => l.wrapping_sub(r),
+					BinaryOp::Shl => l.wrapping_shl(r as u32),
+					BinaryOp::Shr => l.wrapping_shr(r as u32),
+					BinaryOp::Lt => (l < r) as i64,
+					BinaryOp::Le => (l <= r) as i64,
+					BinaryOp::Gt => (l > r) as i64,
+					BinaryOp::Ge => (l >= r) as i64,
+					BinaryOp::Eq => (l == r) as i64,
+					BinaryOp::Ne => (l != r) as i64,
+					BinaryOp::And => l & r,
+					BinaryOp::Xor => l ^ r,
+					BinaryOp::Or => l | r,
+					BinaryOp::LogicalAnd | BinaryOp::LogicalOr => (r != 0) as i64
+				})
+			}
+		}
+	}
+}
+
+pub fn parse(text : &str, symbols : &Symbols) -> Result<Expr, String> {
+	let mut parser = Parser { data : text.as_bytes(), pos : 0, symbols : symbols };
+	let e = try!(parser.expr(0));
+	parser.skip_whitespace();
+	if parser.pos != parser.data.len() {
+		return Err(parser.error("unexpected input"))
+	}
+	Ok(e)
+}
+
+fn var(name : &str) -> Option<Var> {
+	let v = match name {
+		"a" | "b" | "c" | "d" | "e" | "h" | "l" => Var::Reg8(Reg8Operand::from_str(name)),
+		"af" | "bc" | "de" | "hl" | "sp" | "pc" => Var::Reg16(Reg16Operand::from_str(name)),
+		"f" => Var::F,
+		"zf" => Var::Flag(ZERO_FLAG),
+		"nf" => Var::Flag(SUB_FLAG),
+		"hf" => Var::Flag(HALFCARRY_FLAG),
+		"cf" => Var::Flag(CARRY_FLAG),
+		"ime" => Var::Ime,
+		"bank" => Var::Bank,
+		"ly" => Var::Ly,
+		_ => return None
+	};
+	Some(v)
+}
+
+//local labels are written Parent.local
+fn is_name_char(c : u8) -> bool {
+	c.is_ascii_alphanumeric() || c == b'_' || c == b'.'
+}
+
+//binary operators by precedence, lowest first. Longer operators come before their prefixes.
+const BINARY_OPS : [&'static [(&'static str, BinaryOp)]; 10] = [
+	&[("||", BinaryOp::LogicalOr)],
+	&[("&&", BinaryOp::LogicalAnd)],
+	&[("|", BinaryOp::Or)],
+	&[("^", BinaryOp::Xor)],
+	&[("&", BinaryOp::And)],
+	&[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+	&[("<=", BinaryOp::Le), (">=", BinaryOp::Ge), ("<", BinaryOp::Lt), (">", BinaryOp::Gt)],
+	&[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+	&[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+	&[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)]
+];
+
+struct Parser<'a> {
+	data : &'a [u8],
+	pos : usize,
+	symbols : &'a Symbols
+}
+
+impl<'a> Parser<'a> {
+
+	fn error(&self, msg : &str) -> String {
+		format!("{} at offset {}", msg, self.pos)
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.data.get(self.pos).cloned()
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(b' ') | Some(b'\t') = self.peek() {
+			self.pos += 1;
+		}
+	}
+
+	fn expect(&mut self, c : u8) -> Result<(), String> {
+		self.skip_whitespace();
+		if self.peek() == Some(c) {
+			self.pos += 1;
+			Ok(())
+		} else {
+			Err(self.error(&format!("expected '{}'", c as char)))
+		}
+	}
+
+	//the operator at the current position out of `ops`, without consuming it
+	fn binary_op(&mut self, ops : &[(&'static str, BinaryOp)]) -> Option<(&'static str, BinaryOp)> {
+		self.skip_whitespace();
+		let rest = &self.data[self.pos..];
+		for &(s, op) in ops {
+			if rest.starts_with(s.as_bytes()) {
+				//don't take the & of && or the | of ||
+				let next = rest.get(s.len()).cloned();
+				if (s == "&" && next == Some(b'&')) || (s == "|" && next == Some(b'|')) {
+					continue
+				}
+				return Some((s, op))
+			}
+		}
+		None
+	}
+
+	fn expr(&mut self, level : usize) -> Result<Expr, String> {
+		if level == BINARY_OPS.len() {
+			return self.unary()
+		}
+		let mut left = try!(self.expr(level + 1));
+		while let Some((s, op)) = self.binary_op(BINARY_OPS[level]) {
+			self.pos += s.len();
+			let right = try!(self.expr(level + 1));
+			left = Expr::Binary(op, Box::new(left), Box::new(right));
+		}
+		Ok(left)
+	}
+
+	fn unary(&mut self) -> Result<Expr, String> {
+		self.skip_whitespace();
+		let op = match self.peek() {
+			Some(b'-') => UnaryOp::Neg,
+			Some(b'!') => UnaryOp::Not,
+			Some(b'~') => UnaryOp::Complement,
+			_ => return self.primary()
+		};
+		self.pos += 1;
+		let e = try!(self.unary());
+		Ok(Expr::Unary(op, Box::new(e)))
+	}
+
+	fn primary(&mut self) -> Result<Expr, String> {
+		self.skip_whitespace();
+		match self.peek() {
+			Some(b'(') => {
+				self.pos += 1;
+				let e = try!(self.expr(0));
+				try!(self.expect(b')'));
+				Ok(e)
+			},
+			Some(b'[') => {
+				self.pos += 1;
+				let first = try!(self.expr(0));
+				self.skip_whitespace();
+				//[bank:addr]
+				let e = if self.peek() == Some(b':') {
+					self.pos += 1;
+					let addr = try!(self.expr(0));
+					Expr::Memory(Some(Box::new(first)), Box::new(addr))
+				} else {
+					Expr::Memory(None, Box::new(first))
+				};
+				try!(self.expect(b']'));
+				Ok(e)
+			},
+			Some(c) if is_name_char(c) => {
+				let start = self.pos;
+				while let Some(c) = self.peek() {
+					if !is_name_char(c) {
+						break
+					}
+					self.pos += 1;
+				}
+				let name = str::from_utf8(&self.data[start..self.pos]).unwrap();
+				let word = name.to_lowercase();
+				if let Some(v) = var(&word) {
+					return Ok(Expr::Var(v))
+				}
+				if let Some(addr) = self.symbols.lookup(name) {
+					return Ok(Expr::Label(addr))
+				}
+				let digits = if word.starts_with("0x") { &word[2..] } else { &word[..] };
+				match i64::from_str_radix(digits, 16) {
+					Ok(n) => Ok(Expr::Number(n)),
+					Err(_) => {
+						self.pos = start;
+						Err(self.error(&format!("unknown name {}", word)))
+					}
+				}
+			},
+			Some(_) => Err(self.error("expected a value")),
+			None => Err(self.error("unexpected end of expression"))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs::File;
+	use std::io::Read;
+
+	use core::cpu::CPU;
+	use address::BankAddr;
+	use rom::Rom;
+	use symbols::Symbols;
+	use system;
+	use super::parse;
+
+	const ROM : &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms/serial.gb");
+
+	fn test_machine() -> CPU {
+		system::init(Rom::create_from_file(ROM).unwrap())
+	}
+
+	fn test_symbols() -> Symbols {
+		let mut symbols = Symbols::new();
+		symbols.add("wCounter", BankAddr::new(0xc010));
+		symbols.add("Main.loop", BankAddr::new(0x0150));
+		symbols.add("Data", BankAddr { bank : Some(1), addr : 0x4002 });
+		//a label that looks like a number
+		symbols.add("beef", BankAddr::new(0x1234));
+		symbols
+	}
+
+	fn eval(text : &str) -> Result<i64, String> {
+		let mut cpu = test_machine();
+		cpu.regs.af = 0x12b0;
+		cpu.regs.bc = 0x0203;
+		cpu.regs.hl = 0xc010;
+		cpu.sys.write8(0xc010, 0x42);
+		cpu.sys.write8(0xc011, 0x43);
+		parse(text, &test_symbols()).and_then(|e| e.eval(&mut cpu))
+	}
+
+	#[test]
+	fn precedence() {
+		assert_eq!(eval("2+3*4"), Ok(0xe));
+		assert_eq!(eval("(2+3)*4"), Ok(0x14));
+		assert_eq!(eval("10-4-2"), Ok(0xa));
+		assert_eq!(eval("1+1<<2"), Ok(8));
+		assert_eq!(eval("1|2^3&1"), Ok(3));
+		assert_eq!(eval("1+2==3 && 4>3"), Ok(1));
+		assert_eq!(eval("0 || 2<1 || 3"), Ok(1));
+		assert_eq!(eval("-1+2"), Ok(1));
+		assert_eq!(eval("!0+~0"), Ok(0));
+		assert_eq!(eval("7%4*2"), Ok(6));
+	}
+
+	#[test]
+	fn and_operators() {
+		assert_eq!(eval("6&3"), Ok(2));
+		assert_eq!(eval("6&&3"), Ok(1));
+		assert_eq!(eval("6 & 3 && 0"), Ok(0));
+		assert_eq!(eval("4|1||0"), Ok(1));
+		assert_eq!(eval("4|1"), Ok(5));
+		//the right side isn't needed
+		assert_eq!(eval("0 && 1/0"), Ok(0));
+		assert_eq!(eval("1 || 1/0"), Ok(1));
+	}
+
+	#[test]
+	fn memory() {
+		let mut rom = Vec::new();
+		File::open(ROM).unwrap().read_to_end(&mut rom).unwrap();
+		assert_eq!(eval("[c010]"), Ok(0x42));
+		assert_eq!(eval("[hl+1]"), Ok(0x43));
+		assert_eq!(eval("[wCounter]"), Ok(0x42));
+		assert_eq!(eval("[[wCounter] + bfce]"), Ok(0x42));
+		assert_eq!(eval("[0:150]"), Ok(rom[0x150] as i64));
+		assert_eq!(eval("[1:4002]"), Ok(rom[0x4002] as i64));
+		assert_eq!(eval("[1 : 4000+2]"), Ok(rom[0x4002] as i64));
+		//a banked label reads its own bank
+		assert_eq!(eval("[Data]"), Ok(rom[0x4002] as i64));
+		assert!(eval("[1:150]").is_err());
+	}
+
+	#[test]
+	fn names_and_numbers() {
+		//registers come first, then labels, then hex numbers
+		assert_eq!(eval("a"), Ok(0x12));
+		assert_eq!(eval("0a"), Ok(0xa));
+		assert_eq!(eval("C"), Ok(0x03));
+		assert_eq!(eval("bc"), Ok(0x0203));
+		assert_eq!(eval("0bc"), Ok(0xbc));
+		assert_eq!(eval("0xbc"), Ok(0xbc));
+		assert_eq!(eval("zf"), Ok(1));
+		assert_eq!(eval("cf"), Ok(1));
+		assert_eq!(eval("nf"), Ok(0));
+		assert_eq!(eval("beef"), Ok(0x1234));
+		assert_eq!(eval("0beef"), Ok(0xbeef));
+		assert_eq!(eval("Main.loop"), Ok(0x150));
+		assert_eq!(eval("wCounter+1"), Ok(0xc011));
+	}
+
+	#[test]
+	fn errors() {
+		assert!(eval("").is_err());
+		assert!(eval("1+").is_err());
+		assert!(eval("(1").is_err());
+		assert!(eval("[c000").is_err());
+		assert!(eval("1 2").is_err());
+		assert!(eval("nosuchlabel").is_err());
+		assert!(eval("1/0").is_err());
+		assert!(eval("5%0").is_err());
+		assert_eq!(parse("1 + xyz", &test_symbols()).err(), Some("unknown name xyz at offset 4".to_string()));
+		//not a name character, and no panic on the multibyte sequence
+		assert!(eval("\u{e9}").is_err());
+		assert!(eval("a+\u{c3}\u{a9}").is_err());
+	}
+}
@@ -0,0 +1,369 @@
+use std::net::{TcpListener, TcpStream};
+use std::io::{self, Read, Write, ErrorKind};
+use std::str;
+
+use core::cpu::CPU;
+use core::operands::Reg16Operand;
+use system::watchpoint::{Watchpoint, WatchKind};
+use gui::GUI;
+
+//GDB remote serial protocol, enough for breakpoints, watchpoints, stepping and memory.
+//Registers are af, bc, de, hl, sp and pc, 16 bits each in little endian like everything
+//else in the protocol. gdb has no SM83 architecture, the target description only names them.
+
+const REGISTERS : [Reg16Operand; 6] = [Reg16Operand::af, Reg16Operand::bc, Reg16Operand::de,
+									   Reg16Operand::hl, Reg16Operand::sp, Reg16Operand::pc];
+
+const TARGET_XML : &'static str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<feature name="org.rustyboy.sm83">
+<reg name="af" bitsize="16" type="int" regnum="0"/>
+<reg name="bc" bitsize="16" type="int"/>
+<reg name="de" bitsize="16" type="int"/>
+<reg name="hl" bitsize="16" type="int"/>
+<reg name="sp" bitsize="16" type="data_ptr"/>
+<reg name="pc" bitsize="16" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+//instructions between checks for an interrupt from gdb while running
+const POLL_INTERVAL : u32 = 4096;
+
+const SIGINT : u8 = 2;
+const SIGILL : u8 = 4;
+const SIGTRAP : u8 = 5;
+
+//what to do after a packet
+enum Action {
+	Reply(String),
+	Detach,
+	Kill
+}
+
+struct Connection {
+	stream : TcpStream,
+	//for resending after a NAK
+	last_packet : Vec<u8>
+}
+
+impl Connection {
+
+	//the next packet, None when gdb hung up. A lone ^C while stopped comes back as "\x03".
+	fn read_packet(&mut self) -> io::Result<Option<String>> {
+		let mut data = Vec::new();
+		let mut in_packet = false;
+		loop {
+			let c = match try!(self.read_byte()) {
+				Some(c) => c,
+				None => return Ok(None)
+			};
+			if !in_packet {
+				match c {
+					b'$' => in_packet = true,
+					b'-' => {
+						let packet = self.last_packet.clone();
+						try!(self.stream.write_all(&packet));
+					},
+					0x03 => return Ok(Some("\x03".to_string())),
+					//acks and noise
+					_ => {}
+				}
+				continue
+			}
+			if c != b'#' {
+				data.push(c);
+				continue
+			}
+			let mut checksum = [0; 2];
+			try!(self.stream.read_exact(&mut checksum));
+			let expected = str::from_utf8(&checksum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+			if expected != Some(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))) {
+				try!(self.stream.write_all(b"-"));
+				data.clear();
+				in_packet = false;
+				continue
+			}
+			try!(self.stream.write_all(b"+"));
+			return Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+		}
+	}
+
+	fn read_byte(&mut self) -> io::Result<Option<u8>> {
+		let mut buf = [0; 1];
+		match try!(self.stream.read(&mut buf)) {
+			0 => Ok(None),
+			_ => Ok(Some(buf[0]))
+		}
+	}
+
+	fn send(&mut self, data : &str) -> io::Result<()> {
+		let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+		self.last_packet = format!("${}#{:>02x}", data, checksum).into_bytes();
+		let packet = self.last_packet.clone();
+		self.stream.write_all(&packet)
+	}
+
+	//true if gdb sent ^C while the CPU is running
+	fn interrupted(&mut self) -> io::Result<bool> {
+		try!(self.stream.set_nonblocking(true));
+		let mut buf = [0; 64];
+		let result = self.stream.read(&mut buf);
+		try!(self.stream.set_nonblocking(false));
+		match result {
+			Ok(n) => Ok(buf[..n].contains(&0x03)),
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+			Err(e) => Err(e)
+		}
+	}
+}
+
+//wait for gdb on `port` and let it control the CPU until it detaches
+pub fn serve(mut cpu : CPU, mut gui : GUI, port : u16) -> io::Result<()> {
+	let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+	println!("waiting for gdb on port {}...", port);
+	let (stream, addr) = try!(listener.accept());
+	println!("gdb connected from {}", addr);
+	try!(stream.set_nodelay(true));
+	let mut conn = Connection { stream : stream, last_packet : Vec::new() };
+
+	//gdb sets its own
+	cpu.sys.watchpoints.clear();
+	//breakpoints and single steps need every instruction interpreted
+	cpu.disable_jit();
+	let mut breakpoints : Vec<u16> = Vec::new();
+	loop {
+		let packet = match try!(conn.read_packet()) {
+			Some(p) => p,
+			None => {
+				println!("gdb disconnected");
+				return Ok(())
+			}
+		};
+		match try!(handle_packet(&mut cpu, &mut gui, &mut conn, &mut breakpoints, &packet)) {
+			Action::Reply(reply) => try!(conn.send(&reply)),
+			Action::Detach => {
+				try!(conn.send("OK"));
+				println!("gdb detached");
+				return Ok(())
+			},
+			Action::Kill => return Ok(())
+		}
+	}
+}
+
+fn handle_packet(cpu : &mut CPU, gui : &mut GUI, conn : &mut Connection, breakpoints : &mut Vec<u16>, packet : &str) -> io::Result<Action> {
+	if packet == "\x03" {
+		return Ok(Action::Reply(stop_reply(SIGINT)))
+	}
+	if packet.is_empty() || !packet.is_char_boundary(1) {
+		return Ok(Action::Reply(String::new()))
+	}
+	let (cmd, args) = packet.split_at(1);
+	let reply = match cmd {
+		"?" => stop_reply(if cpu.locked_up { SIGILL } else { SIGTRAP }),
+		"g" => REGISTERS.iter().map(|&r| hex_u16(cpu.regs.get16(r))).collect(),
+		"G" => match parse_hex(args) {
+			Some(ref bytes) if bytes.len() >= 2 * REGISTERS.len() => {
+				for (i, &r) in REGISTERS.iter().enumerate() {
+					cpu.regs.set16(r, ((bytes[2 * i + 1] as u16) << 8) | bytes[2 * i] as u16);
+				}
+				"OK".to_string()
+			},
+			_ => "E01".to_string()
+		},
+		"p" => match usize::from_str_radix(args, 16).ok().and_then(|i| REGISTERS.get(i)) {
+			Some(&r) => hex_u16(cpu.regs.get16(r)),
+			None => "E01".to_string()
+		},
+		"P" => {
+			let mut parts = args.splitn(2, '=');
+			let reg = parts.next().and_then(|s| usize::from_str_radix(s, 16).ok()).and_then(|i| REGISTERS.get(i));
+			match (reg, parts.next().and_then(parse_u16_le)) {
+				(Some(&r), Some(v)) => {
+					cpu.regs.set16(r, v);
+					"OK".to_string()
+				},
+				_ => "E01".to_string()
+			}
+		},
+		"m" => match parse_range(args) {
+			Some((addr, len)) => (0..len).map(|i| format!("{:>02x}", cpu.sys.peek8(addr.wrapping_add(i)))).collect(),
+			None => "E01".to_string()
+		},
+		"M" => {
+			let mut parts = args.splitn(2, ':');
+			match (parts.next().and_then(parse_range), parts.next().and_then(parse_hex)) {
+				(Some((addr, len)), Some(data)) if data.len() == len as usize => {
+					for (i, &v) in data.iter().enumerate() {
+						write_memory(cpu, addr.wrapping_add(i as u16), v);
+					}
+					//code may have changed, decoded and translated instructions have to go
+					cpu.flush_code_caches();
+					"OK".to_string()
+				},
+				_ => "E01".to_string()
+			}
+		},
+		"c" | "s" => {
+			if !args.is_empty() {
+				match u16::from_str_radix(args, 16) {
+					Ok(pc) => cpu.regs.pc = pc,
+					Err(_) => return Ok(Action::Reply("E01".to_string()))
+				}
+			}
+			try!(resume(cpu, gui, conn, breakpoints, cmd == "s"))
+		},
+		"Z" | "z" => {
+			let mut parts = args.split(',');
+			let kind = parts.next();
+			let addr = parts.next().and_then(|s| u16::from_str_radix(s, 16).ok());
+			let len = parts.next().and_then(|s| u16::from_str_radix(s, 16).ok()).unwrap_or(1);
+			let insert = cmd == "Z";
+			match (kind, addr) {
+				//software and hardware breakpoints are the same thing here
+				(Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+					breakpoints.retain(|&b| b != addr);
+					if insert {
+						breakpoints.push(addr);
+					}
+					"OK".to_string()
+				},
+				(Some(k @ "2"), Some(addr)) | (Some(k @ "3"), Some(addr)) | (Some(k @ "4"), Some(addr)) => {
+					let kind = match k {
+						"2" => WatchKind::Write,
+						"3" => WatchKind::Read,
+						_ => WatchKind::Access
+					};
+					let end = addr.wrapping_add(len.max(1) - 1);
+					cpu.sys.watchpoints.retain(|w| !(w.start == addr && w.end == end && w.kind == kind));
+					if insert {
+						cpu.sys.watchpoints.push(Watchpoint { start : addr, end : end, kind : kind, value : None });
+					}
+					"OK".to_string()
+				},
+				_ => String::new()
+			}
+		},
+		"q" => query(args),
+		"H" | "T" => "OK".to_string(),
+		"D" => return Ok(Action::Detach),
+		"k" => return Ok(Action::Kill),
+		//anything else is unsupported, gdb falls back to what it knows
+		_ => String::new()
+	};
+	Ok(Action::Reply(reply))
+}
+
+fn query(args : &str) -> String {
+	if args.starts_with("Supported") {
+		"PacketSize=1000;qXfer:features:read+".to_string()
+	} else if args.starts_with("Xfer:features:read:target.xml:") {
+		let range = &args["Xfer:features:read:target.xml:".len()..];
+		match parse_range(range) {
+			Some((offset, len)) => {
+				let offset = (offset as usize).min(TARGET_XML.len());
+				let end = (offset + len as usize).min(TARGET_XML.len());
+				format!("{}{}", if end == TARGET_XML.len() { "l" } else { "m" }, &TARGET_XML[offset..end])
+			},
+			None => "E01".to_string()
+		}
+	} else if args == "Attached" {
+		"1".to_string()
+	} else if args == "C" {
+		"QC1".to_string()
+	} else if args == "fThreadInfo" {
+		"m1".to_string()
+	} else if args == "sThreadInfo" {
+		"l".to_string()
+	} else {
+		String::new()
+	}
+}
+
+//run until a breakpoint, watchpoint, fault or ^C, or just one instruction. Returns the stop reply.
+fn resume(cpu : &mut CPU, gui : &mut GUI, conn : &mut Connection, breakpoints : &[u16], single_step : bool) -> io::Result<String> {
+	let mut count = 0;
+	loop {
+		if cpu.locked_up {
+			return Ok(stop_reply(SIGILL))
+		}
+		let result = cpu.run_instruction();
+		gui.update(cpu);
+		if result.is_err() {
+			return Ok(stop_reply(SIGILL))
+		}
+		if let Some(hit) = cpu.sys.watch_hits.drain(..).next() {
+			let name = match cpu.sys.watchpoints[hit.index].kind {
+				WatchKind::Write => "watch",
+				WatchKind::Read => "rwatch",
+				WatchKind::Access => "awatch"
+			};
+			return Ok(format!("T{:>02x}{}:{:x};", SIGTRAP, name, hit.addr))
+		}
+		if single_step || breakpoints.contains(&cpu.regs.pc) {
+			return Ok(stop_reply(SIGTRAP))
+		}
+		if gui.break_request {
+			return Ok(stop_reply(SIGINT))
+		}
+		count += 1;
+		if count % POLL_INTERVAL == 0 && try!(conn.interrupted()) {
+			return Ok(stop_reply(SIGINT))
+		}
+	}
+}
+
+//ROM is patched in the bank the CPU sees, like "set (bank:addr)" in the prompt
+fn write_memory(cpu : &mut CPU, addr : u16, data : u8) {
+	match addr {
+		0x0000 ... 0x3fff => { cpu.sys.mbc.patch_rom(0, addr, data); },
+		0x4000 ... 0x7fff => {
+			let bank = cpu.sys.mbc.rom_bank() as usize;
+			cpu.sys.mbc.patch_rom(bank, addr, data);
+		},
+		_ => {
+			cpu.sys.write8(addr, data);
+			//gdb's own writes don't trigger its watchpoints
+			cpu.sys.watch_hits.clear();
+		}
+	}
+}
+
+fn stop_reply(signal : u8) -> String {
+	format!("S{:>02x}", signal)
+}
+
+fn hex_u16(v : u16) -> String {
+	format!("{:>02x}{:>02x}", v as u8, (v >> 8) as u8)
+}
+
+fn parse_u16_le(s : &str) -> Option<u16> {
+	match parse_hex(s) {
+		Some(ref bytes) if bytes.len() == 2 => Some(((bytes[1] as u16) << 8) | bytes[0] as u16),
+		_ => None
+	}
+}
+
+//pairs of hex digits, anything else (including non-ASCII) is None
+fn parse_hex(s : &str) -> Option<Vec<u8>> {
+	if s.len() % 2 != 0 {
+		return None
+	}
+	s.as_bytes().chunks(2).map(|pair| {
+		match ((pair[0] as char).to_digit(16), (pair[1] as char).to_digit(16)) {
+			(Some(hi), Some(lo)) => Some((hi << 4 | lo) as u8),
+			_ => None
+		}
+	}).collect()
+}
+
+//"addr,len" in hex
+fn parse_range(s : &str) -> Option<(u16, u16)> {
+	let mut parts = s.splitn(2, ',');
+	match (parts.next().map(|a| u16::from_str_radix(a, 16)), parts.next().map(|l| u16::from_str_radix(l, 16))) {
+		(Some(Ok(addr)), Some(Ok(len))) => Some((addr, len)),
+		_ => None
+	}
+}
@@ -8,11 +8,13 @@ use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
 use std::process;
 use std::collections::HashMap;
+use std::cmp::max;
 
 
 use system::video::*;
 use system::system::GBSystem;
 use core::cpu::CPU;
+use rom;
 
 use self::sdl2::Sdl;
 use self::sdl2::EventPump;
@@ -30,6 +32,9 @@ use self::sdl2::controller::{Button, GameController, Axis};
 
 use std::io::Write;
 use time;
+use savestate;
+use image;
+use input::*;
 
 const FRAME_SAMPLES: u32 = 30;
 
@@ -40,6 +45,10 @@ pub struct GUI<'a> {
 	controller : Option<GameController>,
 	pub break_request: bool,
 	pub speed_mode: bool,
+	pub rewind: bool,
+	save_slot: u8,
+	buttons: Buttons,
+	input: Box<InputSource>,
 	frame_ns: u64,
 	fps:f64
 }
@@ -84,6 +93,10 @@ pub fn init<'a>() -> GUI<'a> {
 		controller : controller,
 		break_request : false,
 		speed_mode : false,
+		rewind : false,
+		save_slot : 0,
+		buttons : Buttons::empty(),
+		input : Box::new(LiveInput),
 		frame_ns : time::precise_time_ns(),
 		fps : 0.0
 	}
@@ -91,14 +104,24 @@ pub fn init<'a>() -> GUI<'a> {
 
 impl<'a> GUI<'a> {
 
-	pub fn update(&mut self, cpu : &mut CPU) {
+	pub fn set_input(&mut self, input : Box<InputSource>) {
+		self.input = input;
+	}
+
+	//loading states and rewinding are refused while a movie is recorded
+	pub fn recording(&self) -> bool {
+		refuse_while_recording(self.input.recording())
+	}
+
+	//returns true if a new frame was presented
+	pub fn update(&mut self, cpu : &mut CPU) -> bool {
 		
 		self.break_request = false;
 		let renderer = &mut self.renderer;
 		let event_pump = &mut self.event_pump;
 			
-		if !cpu.sys.borrow().video.frame_ready {
-			return
+		if !cpu.sys.video.frame_ready {
+			return false
 		}
 		
 		
@@ -110,7 +133,7 @@ impl<'a> GUI<'a> {
 			self.frames = 0;		
 		}
 		{
-			let sys = cpu.sys.borrow();
+			let sys = &cpu.sys;
 			let scy = sys.video.regs.scy.data;
 			let scx = sys.video.regs.scx.data;
 			let wx =  sys.video.regs.wx.data;
@@ -122,6 +145,7 @@ impl<'a> GUI<'a> {
 		}
 		
 		
+        let recording = self.input.recording();
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} => {
@@ -131,67 +155,139 @@ impl<'a> GUI<'a> {
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                 	self.break_request = true;
                 },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => save_state(cpu, self.save_slot),
+                Event::KeyDown { keycode: Some(Keycode::F8), .. } => if !refuse_while_recording(recording) {
+                	load_state(cpu, self.save_slot)
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), .. } => screenshot(cpu, 1),
+                Event::KeyDown { keycode: Some(Keycode::F12), .. } => {
+                	let scale = renderer.window().map_or(1, |w| w.size().0 as usize / SCREEN_WIDTH);
+                	screenshot(cpu, max(scale, 1))
+                },
+                Event::KeyDown { keycode: Some(Keycode::Backspace), repeat: false, .. } => self.rewind = !refuse_while_recording(recording),
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => self.rewind = false,
+                Event::KeyDown { keycode: Some(key), .. } => {
+                	if let Some(slot) = slot_key(key) {
+                		self.save_slot = slot;
+                		println!("selected save slot {}", slot);
+                	}
+                },
 //                Event::ControllerAxisMotion { axis, value: val, .. } => {
 //	                let dead_zone = 5000;
 //	                let neg_zone = val < -dead_zone;
 //	                let pos_zone = val > dead_zone;
 //	                match axis {
 //	                	Axis::LeftX => {
-//	                		cpu.sys.borrow_mut().joypad.set_left_pressed(neg_zone);
-//	                		cpu.sys.borrow_mut().joypad.set_right_pressed(pos_zone)
+//	                		cpu.sys.joypad.set_left_pressed(neg_zone);
+//	                		cpu.sys.joypad.set_right_pressed(pos_zone)
 //	                	},
 //	                	Axis::LeftY => {
-//	                		cpu.sys.borrow_mut().joypad.set_up_pressed(neg_zone);
-//	                		cpu.sys.borrow_mut().joypad.set_down_pressed(pos_zone)
+//	                		cpu.sys.joypad.set_up_pressed(neg_zone);
+//	                		cpu.sys.joypad.set_down_pressed(pos_zone)
 //	                	},
 //	                	_ => {}
 //	                }
 //                },
-	            Event::ControllerButtonDown{ button, .. } => { 
-	            	match button {
-	            		Button::A => cpu.sys.borrow_mut().joypad.set_a_pressed(true),
-	            		Button::B => cpu.sys.borrow_mut().joypad.set_b_pressed(true),
-	            		Button::Start => cpu.sys.borrow_mut().joypad.set_start_pressed(true),
-	            		Button::Back => cpu.sys.borrow_mut().joypad.set_select_pressed(true),
-	            		Button::DPadDown => cpu.sys.borrow_mut().joypad.set_down_pressed(true),
-	            		Button::DPadLeft => cpu.sys.borrow_mut().joypad.set_left_pressed(true),
-	            		Button::DPadUp => cpu.sys.borrow_mut().joypad.set_up_pressed(true),
-	            		Button::DPadRight => cpu.sys.borrow_mut().joypad.set_right_pressed(true),
-	            		Button::RightShoulder => self.speed_mode = true,
-	            		_ => {}
+	            Event::ControllerButtonDown{ button: Button::RightShoulder, .. } => self.speed_mode = true,
+	            Event::ControllerButtonUp{ button: Button::RightShoulder, .. } => self.speed_mode = false,
+	            Event::ControllerButtonDown{ button, .. } => {
+	            	if let Some(b) = button_flag(button) {
+	            		self.buttons.insert(b);
 	            	}
 	            },
-	            Event::ControllerButtonUp{ button, .. } => { 
-	            	match button {
-	            		Button::A => cpu.sys.borrow_mut().joypad.set_a_pressed(false),
-	            		Button::B => cpu.sys.borrow_mut().joypad.set_b_pressed(false),
-	            		Button::Start => cpu.sys.borrow_mut().joypad.set_start_pressed(false),
-	            		Button::Back => cpu.sys.borrow_mut().joypad.set_select_pressed(false),
-	            		Button::DPadDown => cpu.sys.borrow_mut().joypad.set_down_pressed(false),
-	            		Button::DPadLeft => cpu.sys.borrow_mut().joypad.set_left_pressed(false),
-	            		Button::DPadUp => cpu.sys.borrow_mut().joypad.set_up_pressed(false),
-	            		Button::DPadRight => cpu.sys.borrow_mut().joypad.set_right_pressed(false),
-	            		Button::RightShoulder => self.speed_mode = false,
-	            		_ => {}
+	            Event::ControllerButtonUp{ button, .. } => {
+	            	if let Some(b) = button_flag(button) {
+	            		self.buttons.remove(b);
 	            	}
 	            },
                 _ => {}
             }
         }
+
+        //the input source decides what the game sees, e.g. a movie being played back
+        let buttons = self.input.next_frame(self.buttons);
+        cpu.sys.joypad.set_buttons(buttons);
         
-    	cpu.sys.borrow_mut().video.frame_ready = false;
+    	cpu.sys.video.frame_ready = false;
         renderer.set_draw_color(Color::RGBA(0,0,255,128));
         renderer.clear();
     	
         let mut tex = renderer.create_texture_streaming(PixelFormatEnum::RGB332, (160, 144)).unwrap();
 		tex.with_lock(None, |mut buffer: &mut [u8], pitch: usize| {
-				let bb : &[u8; 160*144] = &cpu.sys.borrow().video.back_buffer;
+				let bb : &[u8; 160*144] = &cpu.sys.video.back_buffer;
 				buffer.write(bb).unwrap();
 				
 		}).unwrap();	        
         
         renderer.copy(&tex, None, None);
         renderer.present();
+        true
+
+	}
+}
+
+fn save_state(cpu : &CPU, slot : u8) {
+	let filename = savestate::slot_filename(&cpu.sys.mbc.rom.filename, slot);
+	match savestate::save_to_file(cpu, &filename) {
+		Ok(_) => println!("saved state to slot {}", slot),
+		Err(e) => println!("Couldn't save state: {}", e)
+	}
+}
 
+fn load_state(cpu : &mut CPU, slot : u8) {
+	let filename = savestate::slot_filename(&cpu.sys.mbc.rom.filename, slot);
+	match savestate::load_from_file(cpu, &filename) {
+		Ok(_) => println!("loaded state from slot {}", slot),
+		Err(e) => println!("Couldn't load state: {}", e)
 	}
-}
\ No newline at end of file
+}
+
+//native size or enlarged `scale` times
+pub fn screenshot(cpu : &CPU, scale : usize) {
+	let filename = {
+		let sys = &cpu.sys;
+		let path_wo_extension = rom::path_with_extension(&sys.mbc.rom.filename, "");
+		image::numbered_filename(&format!("{}_shot", path_wo_extension))
+	};
+	match image::write_screenshot(&filename, &cpu.sys.video.back_buffer[..], scale) {
+		Ok(_) => println!("saved screenshot {}", filename),
+		Err(e) => println!("Couldn't save screenshot: {}", e)
+	}
+}
+
+fn slot_key(key : Keycode) -> Option<u8> {
+	match key {
+		Keycode::Num0 => Some(0),
+		Keycode::Num1 => Some(1),
+		Keycode::Num2 => Some(2),
+		Keycode::Num3 => Some(3),
+		Keycode::Num4 => Some(4),
+		Keycode::Num5 => Some(5),
+		Keycode::Num6 => Some(6),
+		Keycode::Num7 => Some(7),
+		Keycode::Num8 => Some(8),
+		Keycode::Num9 => Some(9),
+		_ => None
+	}
+}
+
+fn button_flag(button : Button) -> Option<Buttons> {
+	match button {
+		Button::A => Some(BUTTON_A),
+		Button::B => Some(BUTTON_B),
+		Button::Start => Some(BUTTON_START),
+		Button::Back => Some(BUTTON_SELECT),
+		Button::DPadDown => Some(BUTTON_DOWN),
+		Button::DPadLeft => Some(BUTTON_LEFT),
+		Button::DPadUp => Some(BUTTON_UP),
+		Button::DPadRight => Some(BUTTON_RIGHT),
+		_ => None
+	}
+}
+
+fn refuse_while_recording(recording : bool) -> bool {
+	if recording {
+		println!("Can't go back in time while recording a movie");
+	}
+	recording
+}
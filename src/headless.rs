@@ -0,0 +1,119 @@
+use std::fmt;
+use std::io;
+
+use core::cpu::{CPU, EmulationError};
+use input::{InputSource, Buttons};
+use image;
+
+//when to stop a headless run. Conditions that are None never trigger.
+#[derive(Default)]
+pub struct ExitConditions {
+	pub frames : Option<u64>,
+	pub cycles : Option<u64>,
+	pub pc : Option<u16>,
+	pub serial : Option<String>
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ExitReason {
+	Frames,
+	Cycles,
+	PC,
+	Serial,
+	Fault(EmulationError)
+}
+
+impl fmt::Display for ExitReason {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		let s = match *self {
+			ExitReason::Frames => "frame limit reached",
+			ExitReason::Cycles => "cycle limit reached",
+			ExitReason::PC => "PC reached",
+			ExitReason::Serial => "serial output matched",
+			ExitReason::Fault(ref e) => return write!(f, "{}", e)
+		};
+		write!(f, "{}", s)
+	}
+}
+
+//runs the emulation without a window
+pub struct Headless {
+	input : Box<InputSource>,
+	pub frames : u64,
+	//save <screenshot_prefix>_frame_N.png after each of these frames
+	pub screenshot_frames : Vec<u64>,
+	pub screenshot_prefix : String
+}
+
+impl Headless {
+
+	pub fn new(input : Box<InputSource>) -> Headless {
+		Headless {
+			input : input,
+			frames : 0,
+			screenshot_frames : Vec::new(),
+			screenshot_prefix : String::new()
+		}
+	}
+
+	//does the frame bookkeeping the GUI does otherwise. Returns true at the end of a frame.
+	pub fn update(&mut self, cpu : &mut CPU) -> bool {
+		if !cpu.sys.video.frame_ready {
+			return false
+		}
+		self.frames += 1;
+		if self.screenshot_frames.contains(&self.frames) {
+			let filename = format!("{}_frame_{}.png", self.screenshot_prefix, self.frames);
+			match dump_framebuffer(cpu, &filename) {
+				Ok(_) => println!("saved screenshot {}", filename),
+				Err(e) => println!("Couldn't write {}: {}", filename, e)
+			}
+		}
+		let sys = &mut cpu.sys;
+		//nobody presses buttons here, but a movie may
+		let buttons = self.input.next_frame(Buttons::empty());
+		sys.joypad.set_buttons(buttons);
+		sys.video.frame_ready = false;
+		true
+	}
+
+	pub fn run(&mut self, cpu : &mut CPU, exit : &ExitConditions) -> ExitReason {
+		let mut serial_len = 0;
+		loop {
+			if let Err(e) = cpu.run_instruction() {
+				return ExitReason::Fault(e)
+			}
+			if self.update(cpu) {
+				if exit.frames.map_or(false, |n| self.frames >= n) {
+					return ExitReason::Frames
+				}
+			}
+			if exit.cycles.map_or(false, |n| cpu.cycles >= n) {
+				return ExitReason::Cycles
+			}
+			if exit.pc == Some(cpu.regs.pc) {
+				return ExitReason::PC
+			}
+			if let Some(ref s) = exit.serial {
+				//only search again when something new arrived
+				let sys = &cpu.sys;
+				let output = sys.serial_output();
+				if output.len() != serial_len {
+					serial_len = output.len();
+					if contains(output, s.as_bytes()) {
+						return ExitReason::Serial
+					}
+				}
+			}
+		}
+	}
+}
+
+fn contains(haystack : &[u8], needle : &[u8]) -> bool {
+	needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+//write the current back buffer to a PNG file
+pub fn dump_framebuffer(cpu : &CPU, filename : &str) -> io::Result<()> {
+	image::write_screenshot(filename, &cpu.sys.video.back_buffer[..], 1)
+}
@@ -0,0 +1,79 @@
+extern crate png;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use self::png::HasParameters;
+use system::video::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+//write 8-bit RGB pixel data to a PNG file
+pub fn write_png<P: AsRef<Path>>(path : P, width : usize, height : usize, rgb : &[u8]) -> io::Result<()> {
+	assert_eq!(rgb.len(), width*height*3);
+	let file = try!(File::create(path));
+	let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+	encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+	let mut writer = try!(encoder.write_header());
+	try!(writer.write_image_data(rgb));
+	Ok(())
+}
+
+//read a PNG file as 8-bit RGB. Returns (width, height, pixels).
+pub fn read_png<P: AsRef<Path>>(path : P) -> io::Result<(usize, usize, Vec<u8>)> {
+	let decoder = png::Decoder::new(try!(File::open(path)));
+	let (info, mut reader) = try!(decoder.read_info());
+	let mut buf = vec![0; reader.output_buffer_size()];
+	try!(reader.next_frame(&mut buf));
+	let (color_type, _) = reader.output_color_type();
+	let samples = color_type.samples();
+	let mut rgb = Vec::with_capacity((info.width*info.height*3) as usize);
+	for pixel in buf.chunks(samples) {
+		match samples {
+			1 | 2 => rgb.extend_from_slice(&[pixel[0], pixel[0], pixel[0]]),
+			_ => rgb.extend_from_slice(&pixel[0..3])
+		}
+	}
+	Ok((info.width as usize, info.height as usize, rgb))
+}
+
+//expand the RGB332 pixels of the video back buffer to 8-bit RGB
+pub fn rgb332_to_rgb(pixels : &[u8]) -> Vec<u8> {
+	let mut rgb = Vec::with_capacity(pixels.len()*3);
+	for p in pixels {
+		let (r, g, b) = ((p >> 5) & 0x7, (p >> 2) & 0x7, p & 0x3);
+		rgb.push((r as u32 * 255 / 7) as u8);
+		rgb.push((g as u32 * 255 / 7) as u8);
+		rgb.push((b as u32 * 255 / 3) as u8);
+	}
+	rgb
+}
+
+//save a 160x144 RGB332 back buffer as PNG, enlarged `scale` times
+pub fn write_screenshot<P: AsRef<Path>>(path : P, pixels : &[u8], scale : usize) -> io::Result<()> {
+	assert_eq!(pixels.len(), SCREEN_WIDTH*SCREEN_HEIGHT);
+	let rgb = rgb332_to_rgb(pixels);
+	if scale <= 1 {
+		return write_png(path, SCREEN_WIDTH, SCREEN_HEIGHT, &rgb)
+	}
+	let (width, height) = (SCREEN_WIDTH*scale, SCREEN_HEIGHT*scale);
+	let mut scaled = Vec::with_capacity(width*height*3);
+	for y in 0..height {
+		let row = &rgb[(y/scale)*SCREEN_WIDTH*3..(y/scale + 1)*SCREEN_WIDTH*3];
+		for x in 0..width {
+			scaled.extend_from_slice(&row[(x/scale)*3..(x/scale)*3 + 3]);
+		}
+	}
+	write_png(path, width, height, &scaled)
+}
+
+//first <prefix>_NNN.png that doesn't exist yet
+pub fn numbered_filename(prefix : &str) -> String {
+	let mut n = 1;
+	loop {
+		let filename = format!("{}_{:03}.png", prefix, n);
+		if !Path::new(&filename).exists() {
+			return filename
+		}
+		n += 1;
+	}
+}
@@ -0,0 +1,29 @@
+bitflags! {
+    flags Buttons: u8 {
+        const BUTTON_A      = 1<<0,
+        const BUTTON_B      = 1<<1,
+        const BUTTON_SELECT = 1<<2,
+        const BUTTON_START  = 1<<3,
+        const BUTTON_RIGHT  = 1<<4,
+        const BUTTON_LEFT   = 1<<5,
+        const BUTTON_UP     = 1<<6,
+        const BUTTON_DOWN   = 1<<7
+    }
+}
+
+//decides which buttons are held during the next frame
+pub trait InputSource {
+	//`live` are the buttons the user is currently pressing
+	fn next_frame(&mut self, live : Buttons) -> Buttons;
+	//true while frames are being recorded. The emulated time must not jump back then,
+	//or the recording doesn't match what happened.
+	fn recording(&self) -> bool { false }
+}
+
+pub struct LiveInput;
+
+impl InputSource for LiveInput {
+	fn next_frame(&mut self, live : Buttons) -> Buttons {
+		live
+	}
+}
@@ -5,10 +5,23 @@ mod system;
 mod gui;
 mod prompt;
 mod logger;
+mod image;
+mod savestate;
+mod rewind;
+mod input;
+mod movie;
+mod headless;
+mod testrunner;
+mod expr;
+mod address;
+mod symbols;
+mod gdbstub;
 
 extern crate getopts;
 #[macro_use] extern crate log;
 extern crate time;
+#[cfg(test)]
+extern crate serde_json;
 
 #[macro_use]
 extern crate bitflags;
@@ -19,10 +32,21 @@ use std::process;
 use std::io::Write;
 use std::fs::File;
 use getopts::Options;
+use core::cpu::CPU;
 use core::instruction::{Instruction, InstructionType};
 use core::operands::{Reg16Operand,Operand,CCOperand};
 use rom::*;
+use system::tcplink::TcpLink;
+use system::printer::Printer;
+use system::serial::{SerialLink, NoLink, CaptureLink};
+use rewind::RewindBuffer;
+use movie::{MovieRecorder, MoviePlayer};
+use input::{InputSource, LiveInput};
+use headless::{Headless, ExitConditions, ExitReason};
+use symbols::Symbols;
 
+const REWIND_SNAPSHOTS : usize = 600;
+const REWIND_INTERVAL : u32 = 2; //frames
 
 fn main() {
     let args : Vec<_> = env::args().collect();
@@ -33,6 +57,23 @@ fn main() {
     opts.optflag("h", "help", "print this help information");
     opts.optflag("l", "log", "enable logging (disabled by default)");
     opts.optopt("t", "trace", "set trace output file name", "FILE");
+    opts.optopt("", "listen", "wait for a link cable partner on localhost:PORT", "PORT");
+    opts.optopt("", "connect", "connect the link cable to another instance", "HOST:PORT");
+    opts.optflag("", "printer", "attach a Game Boy Printer to the link port");
+    opts.optopt("", "serial-out", "write serial output to FILE (- for stdout)", "FILE");
+    opts.optopt("", "record", "record joypad input from power-on to a movie FILE", "FILE");
+    opts.optopt("", "play", "play back a movie FILE", "FILE");
+    opts.optflag("", "headless", "run without a window until an exit condition is met");
+    opts.optopt("", "frames", "headless: stop after N frames", "N");
+    opts.optopt("", "cycles", "headless: stop after N clock cycles", "N");
+    opts.optopt("", "until-pc", "headless: stop when PC reaches ADDR (hex)", "ADDR");
+    opts.optopt("", "until-serial", "headless: stop when the serial output contains TEXT", "TEXT");
+    opts.optopt("", "dump", "headless: write the final framebuffer to a PNG FILE", "FILE");
+    opts.optopt("", "test-roms", "run all test ROMs in DIR and print the results", "DIR");
+    opts.optopt("", "screenshot-at", "headless: save screenshots after the given frames", "N,M,...");
+    opts.optopt("", "gdb", "let gdb control the emulator over TCP on localhost:PORT", "PORT");
+    opts.optopt("", "history", "keep the last N instructions and show them when the CPU crashes", "N");
+    opts.optflag("", "jit", "translate hot code to x86-64 (needs the jit feature). Not used by the debugger or --gdb");
     
     let progname = args[0].clone();
     
@@ -53,6 +94,29 @@ fn main() {
     	logger::init().unwrap();
     }
     
+    let jit = matches.opt_present("jit");
+    if jit && !cfg!(feature = "jit") {
+    	println!("Error: built without the jit feature");
+    	process::exit(1)
+    }
+    //translated blocks don't stop in the middle
+    if jit && matches.opt_present("until-pc") {
+    	println!("Error: --until-pc can't be used with --jit");
+    	process::exit(1)
+    }
+    
+    if let Some(dir) = matches.opt_str("test-roms") {
+    	let frame_limit = matches.opt_str("frames").map_or(testrunner::DEFAULT_FRAME_LIMIT, |s| parse_or_exit(&s, 10, "frame count"));
+    	match testrunner::run_directory(&dir, frame_limit, jit) {
+    		Ok(true) => return,
+    		Ok(false) => process::exit(1),
+    		Err(e) => {
+    			println!("Couldn't run tests in {}: {}", dir, e);
+    			process::exit(1)
+    		}
+    	}
+    }
+    
 	//positional arguments
 	if matches.free.len() != 1 {
     	print_usage(opts, &progname);
@@ -69,25 +133,161 @@ fn main() {
         }
     };
     
-    let (mut cpu, sys) = system::init(rom);
+    let mut cpu = system::init(rom);
+    
+    if let Some(result) = Symbols::load_for_rom(&romfile) {
+    	match result {
+    		Ok((filename, symbols)) => {
+    			println!("loaded {} symbols from {}", symbols.len(), filename);
+    			cpu.symbols = symbols;
+    		},
+    		Err(e) => println!("Error: {}", e)
+    	}
+    }
 	    
+    if jit {
+    	cpu.enable_jit();
+    }
+    
+    if let Some(n) = matches.opt_str("history") {
+    	cpu.enable_history(parse_or_exit(&n, 10, "instruction count") as usize);
+    }
+    
     if let Some(filename) = matches.opt_str("t") {
     	cpu.set_trace_file(File::create(filename).unwrap())
     }
+    
+    let mut link : Box<SerialLink> = Box::new(NoLink);
+    if let Some(port_str) = matches.opt_str("listen") {
+    	let port = match port_str.parse::<u16>() {
+    		Ok(p) => p,
+    		Err(e) => {
+    			println!("Invalid port {}: {}", port_str, e);
+    			process::exit(1)
+    		}
+    	};
+    	link = match TcpLink::listen(port) {
+    		Ok(l) => Box::new(l),
+    		Err(e) => {
+    			println!("Error: {}", e);
+    			process::exit(1)
+    		}
+    	};
+    } else if let Some(addr) = matches.opt_str("connect") {
+    	link = match TcpLink::connect(&addr[..]) {
+    		Ok(l) => Box::new(l),
+    		Err(e) => {
+    			println!("Couldn't connect to {}: {}", addr, e);
+    			process::exit(1)
+    		}
+    	};
+    } else if matches.opt_present("printer") {
+    	let prefix = path_with_extension(&romfile, "");
+    	link = Box::new(Printer::new(&prefix));
+    }
+    
+    let mut sink : Option<Box<Write>> = None;
+    if let Some(filename) = matches.opt_str("serial-out") {
+    	sink = if filename == "-" {
+    		Some(Box::new(std::io::stdout()))
+    	} else {
+    		match File::create(&filename) {
+    			Ok(f) => Some(Box::new(f)),
+    			Err(e) => {
+    				println!("Couldn't create {}: {}", filename, e);
+    				process::exit(1)
+    			}
+    		}
+    	};
+    }
+    if sink.is_some() || matches.opt_present("until-serial") {
+    	link = Box::new(CaptureLink::new(link, sink));
+    }
+    cpu.sys.set_serial_link(link);
+	
+    let input : Box<InputSource> = if let Some(filename) = matches.opt_str("play") {
+    	match MoviePlayer::open(&filename, &mut cpu) {
+    		Ok(player) => Box::new(player),
+    		Err(e) => {
+    			println!("Couldn't play {}: {}", filename, e);
+    			process::exit(1)
+    		}
+    	}
+    } else if let Some(filename) = matches.opt_str("record") {
+    	match MovieRecorder::create(&filename, &mut cpu) {
+    		Ok(recorder) => Box::new(recorder),
+    		Err(e) => {
+    			println!("Couldn't record {}: {}", filename, e);
+    			process::exit(1)
+    		}
+    	}
+    } else {
+    	Box::new(LiveInput)
+    };
+	
+    if matches.opt_present("headless") {
+    	let exit = ExitConditions {
+    		frames : matches.opt_str("frames").map(|s| parse_or_exit(&s, 10, "frame count")),
+    		cycles : matches.opt_str("cycles").map(|s| parse_or_exit(&s, 10, "cycle count")),
+    		pc : matches.opt_str("until-pc").map(|s| parse_u16_or_exit(&s, 16, "address")),
+    		serial : matches.opt_str("until-serial")
+    	};
+    	let mut headless = Headless::new(input);
+    	if let Some(list) = matches.opt_str("screenshot-at") {
+    		headless.screenshot_frames = list.split(',').map(|s| parse_or_exit(s.trim(), 10, "frame number")).collect();
+    		headless.screenshot_prefix = path_with_extension(&romfile, "");
+    	}
+    	let reason = headless.run(&mut cpu, &exit);
+    	println!("stopped after {} frames, {} cycles: {}", headless.frames, cpu.cycles, reason);
+    	if let ExitReason::Fault(_) = reason {
+    		print_history(&cpu);
+    	}
+    	if let Some(filename) = matches.opt_str("dump") {
+    		if let Err(e) = headless::dump_framebuffer(&cpu, &filename) {
+    			println!("Couldn't write {}: {}", filename, e);
+    			process::exit(1)
+    		}
+    	}
+    	return
+    }
 	
 	let mut gui = gui::init();
+	gui.set_input(input);
 	
     if matches.opt_present("i") {
-    	prompt::show(cpu, sys, gui);
+    	prompt::show(cpu, gui);
+    	return
+    }
+    
+    if let Some(port) = matches.opt_str("gdb") {
+    	let port = parse_u16_or_exit(&port, 10, "port");
+    	if let Err(e) = gdbstub::serve(cpu, gui, port) {
+    		println!("gdb connection failed: {}", e);
+    		process::exit(1)
+    	}
     	return
     }
 
 	let mut real_time : f64 = 0.0;
 	let mut emulation_time :f64 = 0.0;
 	let mut t0 = time::precise_time_ns();
+	let mut rewind = RewindBuffer::new(REWIND_SNAPSHOTS, REWIND_INTERVAL);
 	loop {
-		emulation_time += cpu.run_instruction();
-		gui.update(&mut cpu);
+		match cpu.run_instruction() {
+			Ok(t) => emulation_time += t,
+			//the CPU is stuck now, but the window stays usable for loading a state
+			Err(e) => {
+				println!("{}", e);
+				print_history(&cpu);
+			}
+		}
+		if gui.update(&mut cpu) {
+			if gui.rewind {
+				rewind.rewind(&mut cpu);
+			} else {
+				rewind.record_frame(&cpu);
+			}
+		}
 		let t1 = time::precise_time_ns();
 		real_time += (t1 - t0) as f64;
 		t0 = t1;
@@ -101,6 +301,36 @@ fn main() {
 	}	
 }
 
+//how the CPU got where it crashed, if --history was given
+fn print_history(cpu : &CPU) {
+	if let Some(ref history) = cpu.history {
+		println!("last {} instructions:", history.len());
+		for step in history.last(history.len()) {
+			println!("{}", step);
+		}
+	}
+}
+
+fn parse_or_exit(s : &str, radix : u32, what : &str) -> u64 {
+	let digits = if radix == 16 && s.starts_with("0x") { &s[2..] } else { s };
+	match u64::from_str_radix(digits, radix) {
+		Ok(n) => n,
+		Err(e) => {
+			println!("Invalid {} {}: {}", what, s, e);
+			process::exit(1)
+		}
+	}
+}
+
+fn parse_u16_or_exit(s : &str, radix : u32, what : &str) -> u16 {
+	let n = parse_or_exit(s, radix, what);
+	if n > 0xffff {
+		println!("Invalid {} {}: number too large to fit in 16 bits", what, s);
+		process::exit(1)
+	}
+	n as u16
+}
+
 fn print_usage(opts : Options, progname : &str) {
 	let brief = format!("Usage: {} ROM_FILE.gb [options]", progname);
 	print!("{}", opts.usage(&brief));
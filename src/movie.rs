@@ -0,0 +1,192 @@
+use std::io::{self, Read, Write, BufWriter};
+use std::fs::File;
+
+use core::cpu::CPU;
+use input::{InputSource, Buttons};
+use rom::Rom;
+use savestate;
+
+const MAGIC : &'static [u8; 4] = b"RBMV";
+const VERSION : u16 = 2;
+
+const START_POWER_ON : u8 = 0;
+const START_SAVE_STATE : u8 = 1;
+
+//Movie file layout:
+//  "RBMV", version (u16), ROM global checksum (u16), title length (u8), title,
+//  start type (u8), save state or cartridge RAM length (u32), save state or cartridge RAM,
+//  one button byte per frame
+//All numbers are little endian. Version 1 power-on movies have no cartridge RAM.
+
+fn write_blob(w : &mut Write, data : &[u8]) -> io::Result<()> {
+	let len = data.len() as u32;
+	try!(w.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]));
+	w.write_all(data)
+}
+
+//`ram` is the cartridge RAM at power-on, the battery save the movie starts from
+fn write_header(w : &mut Write, rom : &Rom, state : Option<&[u8]>, ram : &[u8]) -> io::Result<()> {
+	let checksum = rom.global_checksum();
+	try!(w.write_all(MAGIC));
+	try!(w.write_all(&[VERSION as u8, (VERSION >> 8) as u8, checksum as u8, (checksum >> 8) as u8]));
+	try!(w.write_all(&[rom.title.len() as u8]));
+	try!(w.write_all(rom.title.as_bytes()));
+	match state {
+		Some(data) => {
+			try!(w.write_all(&[START_SAVE_STATE]));
+			write_blob(w, data)
+		},
+		None => {
+			try!(w.write_all(&[START_POWER_ON]));
+			write_blob(w, ram)
+		}
+	}
+}
+
+fn read_blob<'a>(data : &'a [u8], pos : &mut usize) -> io::Result<&'a [u8]> {
+	let p = *pos;
+	if data.len() < p + 4 {
+		return Err(invalid_data("movie is truncated"))
+	}
+	let len = (data[p] as usize) | ((data[p+1] as usize) << 8) | ((data[p+2] as usize) << 16) | ((data[p+3] as usize) << 24);
+	if data.len() < p + 4 + len {
+		return Err(invalid_data("movie is truncated"))
+	}
+	*pos = p + 4 + len;
+	Ok(&data[p + 4..p + 4 + len])
+}
+
+fn invalid_data(msg : &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+//records the buttons of every frame while passing the live input through
+pub struct MovieRecorder {
+	out : BufWriter<File>,
+	num_frames : u64
+}
+
+impl MovieRecorder {
+
+	//record starting from power-on. The machine must be freshly reset.
+	pub fn create(filename : &str, cpu : &mut CPU) -> io::Result<MovieRecorder> {
+		MovieRecorder::start(filename, cpu, false)
+	}
+
+	//record starting from the current machine state, which is embedded in the movie
+	pub fn create_from_state(filename : &str, cpu : &mut CPU) -> io::Result<MovieRecorder> {
+		MovieRecorder::start(filename, cpu, true)
+	}
+
+	fn start(filename : &str, cpu : &mut CPU, embed_state : bool) -> io::Result<MovieRecorder> {
+		let mut out = BufWriter::new(try!(File::create(filename)));
+		//playback starts with all buttons released, so recording does too
+		cpu.sys.joypad.set_buttons(Buttons::empty());
+		let state = if embed_state { Some(savestate::save(cpu)) } else { None };
+		try!(write_header(&mut out, &cpu.sys.mbc.rom, state.as_ref().map(|s| &s[..]), cpu.sys.mbc.ram()));
+		Ok(MovieRecorder {
+			out : out,
+			num_frames : 0
+		})
+	}
+}
+
+impl InputSource for MovieRecorder {
+
+	fn next_frame(&mut self, live : Buttons) -> Buttons {
+		if let Err(e) = self.out.write_all(&[live.bits()]) {
+			println!("Couldn't record movie frame {}: {}", self.num_frames, e);
+		}
+		self.num_frames += 1;
+		live
+	}
+
+	fn recording(&self) -> bool {
+		true
+	}
+}
+
+impl Drop for MovieRecorder {
+	fn drop(&mut self) {
+		self.out.flush().unwrap_or(());
+	}
+}
+
+//replays a recorded movie, ignoring the live input until the movie ends
+pub struct MoviePlayer {
+	frames : Vec<u8>,
+	position : usize
+}
+
+impl MoviePlayer {
+
+	//read the movie and put the machine into its initial state
+	pub fn open(filename : &str, cpu : &mut CPU) -> io::Result<MoviePlayer> {
+		let mut data = Vec::new();
+		try!(try!(File::open(filename)).read_to_end(&mut data));
+
+		if data.len() < 11 || &data[0..4] != MAGIC {
+			return Err(invalid_data("not a rustyboy movie"))
+		}
+		let version = (data[4] as u16) | ((data[5] as u16) << 8);
+		if version > VERSION {
+			return Err(invalid_data("movie was recorded by a newer version"))
+		}
+		let checksum = (data[6] as u16) | ((data[7] as u16) << 8);
+		let title_len = data[8] as usize;
+		let mut pos = 9 + title_len;
+		if data.len() < pos + 1 {
+			return Err(invalid_data("movie is truncated"))
+		}
+		{
+			let rom = &cpu.sys.mbc.rom;
+			if checksum != rom.global_checksum() || &data[9..pos] != rom.title.as_bytes() {
+				return Err(invalid_data("movie was recorded with a different ROM"))
+			}
+		}
+
+		let start = data[pos];
+		pos += 1;
+		match start {
+			START_POWER_ON => {
+				//we rely on the caller passing a machine that was just powered on,
+				//only the battery save may differ from the recording
+				if version >= 2 {
+					let ram = try!(read_blob(&data, &mut pos));
+					if !cpu.sys.mbc.load_ram(ram) {
+						return Err(invalid_data("cartridge RAM size mismatch"))
+					}
+				}
+			},
+			START_SAVE_STATE => {
+				let state = try!(read_blob(&data, &mut pos));
+				try!(savestate::load(cpu, state));
+			},
+			_ => return Err(invalid_data("invalid movie start type"))
+		}
+		cpu.sys.joypad.set_buttons(Buttons::empty());
+
+		Ok(MoviePlayer {
+			frames : data[pos..].to_vec(),
+			position : 0
+		})
+	}
+
+	pub fn finished(&self) -> bool {
+		self.position >= self.frames.len()
+	}
+}
+
+impl InputSource for MoviePlayer {
+
+	fn next_frame(&mut self, live : Buttons) -> Buttons {
+		if self.finished() {
+			return live
+		}
+		self.position += 1;
+		if self.finished() {
+			println!("movie playback finished after {} frames", self.frames.len());
+		}
+		Buttons::from_bits_truncate(self.frames[self.position - 1])
+	}
+}
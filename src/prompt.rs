@@ -6,10 +6,22 @@ use std::io::{self, Write};
 use std::ffi::{CStr, CString};
 
 use core::cpu::CPU;
+use core::gb::GBRegisters;
+use core::callstack::CallKind;
+use core::instruction::Instruction;
+use core::instruction::InstructionType;
 use core::operands::{Reg8Operand, Reg16Operand};
 use system::system::GBSystem;
+use system::watchpoint::{Watchpoint, WatchKind};
 use rom::*;
-use gui::GUI;
+use gui::{self, GUI};
+use image;
+use expr::{self, Expr};
+use address::BankAddr;
+use symbols::Symbols;
+use savestate;
+use input::LiveInput;
+use movie::{MovieRecorder, MoviePlayer};
 
 macro_rules! extract_arg {
 	($tok:ident, $i:expr, $name:expr) => {
@@ -32,10 +44,51 @@ macro_rules! extract_opt_arg {
 	}
 }
 
-pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
+//instructions kept for history and reverse-step, unless --history said otherwise
+const HISTORY_SIZE : usize = 10000;
 
-	let (mut cpu, mut system) = (cpu, &mut system);
-	let mut breakpoints : Vec<u16> = Vec::new();
+const HELP : [(&'static str, &'static str); 30] = [
+	("b, break", "list breakpoints"),
+	("b, break ADDR [if EXPR]", "stop running when PC reaches ADDR and EXPR is true"),
+	("b, break clear", "delete all breakpoints"),
+	("w, watch [r|w|rw] ADDR[-ADDR] [VALUE]", "stop when the CPU reads, writes (default) or accesses memory, optionally only VALUE"),
+	("w, watch", "list watchpoints"),
+	("w, watch clear", "delete all watchpoints"),
+	("l, list [N [ADDR]]", "disassemble N instructions (10) at PC or ADDR"),
+	("set REG VALUE", "set a register"),
+	("set (ADDR) VALUE...", "write bytes to memory"),
+	("p, print [REG|ADDR]", "show a register or memory byte, all registers without argument"),
+	("x, dump ADDR [N]", "show N bytes of memory (256)"),
+	("p, print f|flags|ime", "show the flags or the interrupt master enable"),
+	("p, print EXPR", "evaluate an expression"),
+	("s, step [N]", "execute N instructions (1) and show what they changed"),
+	("n, next", "execute one instruction, running called functions to their return"),
+	("finish", "run until the current function returns"),
+	("bt, backtrace", "show the calls and interrupts the CPU is in"),
+	("history [N]", "show the last N (20) executed instructions and their writes"),
+	("rs, reverse-step [N]", "undo N instructions (1), memory and registers only"),
+	("run [N]", "run N instructions or until a breakpoint or the break key"),
+	("reset", "reset the CPU"),
+	("load FILE", "load another ROM"),
+	("symbols FILE", "load labels from an RGBDS or no$gmb .sym file"),
+	("savestate [SLOT|FILE]", "save the state to a slot (0) or a file"),
+	("loadstate [SLOT|FILE]", "load the state from a slot (0) or a file"),
+	("record FILE|stop", "record input from the current state to a movie"),
+	("play FILE", "play back a movie"),
+	("screenshot [FILE [SCALE]]", "save the screen to a PNG"),
+	("i, info", "show the ROM header"),
+	("q, quit, exit", "leave rustyboy")
+];
+
+pub fn show(mut cpu : CPU, mut gui : GUI) {
+
+	let mut breakpoints : Vec<Breakpoint> = Vec::new();
+	//translated blocks would run past breakpoints
+	cpu.disable_jit();
+	cpu.call_stack.report_problems = true;
+	if cpu.history.is_none() {
+		cpu.enable_history(HISTORY_SIZE);
+	}
 
 	println!("Welcome to rustyboy.");
 	println!("Type \"help\" for help, or \"exit\" to exit.");
@@ -45,28 +98,107 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 		let tokens : Vec<&str> = cmd.split(char::is_whitespace).collect();
 		
 		match tokens[0] {
-			"b" | "break" => {
-				let addr_str = extract_arg!(tokens, 1, "address literal or clear");
+			"b" | "break" => { //break ADDR [if EXPR]
+				if tokens.len() < 2 || tokens[1].is_empty() {
+					for b in &breakpoints {
+						match b.condition {
+							Some((_, ref text)) => println!("{} if {}", b.addr, text),
+							None => println!("{}", b.addr)
+						}
+					}
+					continue
+				}
+				let addr_str = tokens[1];
 				if addr_str == "clear" {
 					println!("deleted all breakpoints");
 					breakpoints.clear();
 					continue					
 				}
-				let addr = match u16::from_str_radix(&addr_str, 16) {
-					Ok(a) => a,
+				//without a bank, code in switchable ROM means the bank mapped now
+				let addr = match parse_addr(&cpu, addr_str) {
+					Ok(a) => a.resolve(&cpu.sys),
 					Err(e) => {
 						println!("Parse error: {}", e);
 						continue
 					}
 				};
-				if !breakpoints.contains(&addr) {
-					breakpoints.push(addr);
+				let condition = match tokens.get(2) {
+					Some(&"if") => {
+						let text = tokens[3..].join(" ");
+						match expr::parse(&text, &cpu.symbols) {
+							Ok(e) => Some((e, text)),
+							Err(e) => {
+								println!("Parse error: {}", e);
+								continue
+							}
+						}
+					},
+					Some(&"") | None => None,
+					Some(_) => {
+						println!("expected \"if\" after the address");
+						continue
+					}
+				};
+				breakpoints.retain(|b| b.addr != addr);
+				breakpoints.push(Breakpoint { addr : addr, condition : condition });
+				print!("breakpoint at ");
+				print_insn(&mut cpu, addr);
+			},
+			"w" | "watch" => { //watch [r|w|rw] ADDR[-ADDR] [VALUE]
+				let mut args = &tokens[1..];
+				if args.is_empty() || args[0].is_empty() {
+					for (i, w) in cpu.sys.watchpoints.iter().enumerate() {
+						println!("{}: {}", i, w);
+					}
+					continue
 				}
-				let bytes = cpu.fetch(addr);
-				let insn = cpu.decode(bytes);
-				println!("breakpoint at 0x{:>04x}: {}", addr, insn);
+				if args[0] == "clear" {
+					println!("deleted all watchpoints");
+					cpu.sys.watchpoints.clear();
+					continue
+				}
+				let kind = match args[0] {
+					"r" => Some(WatchKind::Read),
+					"w" => Some(WatchKind::Write),
+					"rw" => Some(WatchKind::Access),
+					_ => None
+				};
+				if kind.is_some() {
+					args = &args[1..];
+				}
+				if args.is_empty() {
+					println!("missing argument: address");
+					continue
+				}
+				let mut range = args[0].splitn(2, '-');
+				let start = match parse_addr(&cpu, range.next().unwrap()) {
+					Ok(a) => a.addr,
+					Err(e) => {
+						println!("Parse error: {}", e);
+						continue
+					}
+				};
+				let end = match range.next().map(|s| parse_addr(&cpu, s)) {
+					Some(Ok(a)) if a.addr >= start => a.addr,
+					Some(_) => {
+						println!("Invalid address range");
+						continue
+					},
+					None => start
+				};
+				let value = match args.get(1).map(|s| u8::from_str_radix(s, 16)) {
+					Some(Ok(v)) => Some(v),
+					Some(Err(e)) => {
+						println!("Parse error: {}", e);
+						continue
+					},
+					None => None
+				};
+				let w = Watchpoint { start : start, end : end, kind : kind.unwrap_or(WatchKind::Write), value : value };
+				println!("watchpoint {}: {}", cpu.sys.watchpoints.len(), w);
+				cpu.sys.watchpoints.push(w);
 			},
-			"l" | "list" => { //list next n instructions
+			"l" | "list" => { //list next n instructions at PC or an address
 				let mut n = if let Some(n_str) = extract_opt_arg!(tokens, 1) {  
 					match u16::from_str_radix(&n_str, 10) {
 						Ok(n) => n,
@@ -78,15 +210,48 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 				} else {
 					10
 				};
-				let mut addr = cpu.regs.pc;
+				let mut addr = match extract_opt_arg!(tokens, 2) {
+					Some(s) => match parse_addr(&cpu, s) {
+						Ok(a) => a.resolve(&cpu.sys),
+						Err(e) => {
+							println!("Parse error: {}", e);
+							continue
+						}
+					},
+					None => BankAddr::current(&cpu.sys, cpu.regs.pc)
+				};
 				while n > 0 {
-					let bytes = cpu.fetch(addr);
-					let insn = cpu.decode(bytes);
-					println!("{:>04x}: {}", addr, insn);
-					addr = addr.wrapping_add(insn.length);
+					let length = print_insn(&mut cpu, addr);
+					addr = addr.offset(length);
 					n-=1;
 				}
 			},
+			"x" | "dump" => { //hex dump of memory
+				let start = match parse_addr(&cpu, extract_arg!(tokens, 1, "address")) {
+					Ok(a) => a,
+					Err(e) => {
+						println!("Parse error: {}", e);
+						continue
+					}
+				};
+				let len = match extract_opt_arg!(tokens, 2).map(|s| u32::from_str_radix(s, 10)) {
+					Some(Ok(n)) => n,
+					Some(Err(e)) => {
+						println!("Parse error: {}", e);
+						continue
+					},
+					None => 256
+				};
+				let mut offset = 0;
+				while offset < len {
+					let line = start.offset(offset as u16);
+					let bytes : Vec<u8> = (0..16).take((len - offset) as usize).map(|i| line.offset(i).read(&mut cpu.sys)).collect();
+					let hex : Vec<String> = bytes.iter().map(|b| format!("{:>02x}", b)).collect();
+					let text : String = bytes.iter().map(|&b| if b >= 0x20 && b < 0x7f { b as char } else { '.' }).collect();
+					println!("{}: {:<47}  {}", line, hex.join(" "), text);
+					offset += 16;
+				}
+			},
 			"set" => { // set register to value
 				let mut target_str = extract_arg!(tokens, 1, "register or memory address");
 				let mut values : Vec<u16> = Vec::new();
@@ -109,14 +274,31 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 				if target_str.starts_with("(") && target_str.ends_with(")") {
 					let mut addr_str : String = target_str[1..].to_string();
 					addr_str.pop();
-					match u16::from_str_radix(&addr_str, 16) {
-						Ok(mut addr) => { 
-							for v in &values {
-								system.borrow_mut().write8(addr, *v as u8);
-								addr = addr.wrapping_add(1)							
+					match parse_addr(&cpu, &addr_str) {
+						Ok(start) => {
+							let start = start.resolve(&cpu.sys);
+							for (i, v) in values.iter().enumerate() {
+								let addr = start.addr.wrapping_add(i as u16);
+								match addr {
+									//ROM, patch the image instead of writing to the MBC
+									0x0000 ... 0x7fff => {
+										let bank = match start.bank {
+											Some(b) if addr >= 0x4000 => b,
+											_ => BankAddr::current(&cpu.sys, addr).bank.unwrap_or(0)
+										};
+										if !cpu.sys.mbc.patch_rom(bank as usize, addr, *v as u8) {
+											println!("No ROM bank {:x}", bank);
+											break
+										}
+									},
+									_ => cpu.sys.write8(addr, *v as u8)
+								}
 							}
+							//the debugger's own writes don't trigger watchpoints
+							cpu.sys.watch_hits.clear();
+							cpu.flush_code_caches();
 						},
-						Err(_) => println!("Invalid address")
+						Err(e) => println!("Invalid address: {}", e)
 					}
 					continue			
 				}
@@ -126,8 +308,118 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 					_ => println!("Invalid register") 
 				}
 			}
-			"s" | "step" => { //execute single instruction
-			}, 
+			"s" | "step" => { //execute n instructions, showing what they changed
+				let n = if let Some(n_str) = extract_opt_arg!(tokens, 1) {
+					match u32::from_str_radix(&n_str, 10) {
+						Ok(n) => n,
+						Err(e) => {
+							println!("Parse error: {}", e);
+							continue
+						}
+					}
+				} else {
+					1
+				};
+				for _ in 0..n {
+					let pc = BankAddr::current(&cpu.sys, cpu.regs.pc);
+					print_insn(&mut cpu, pc);
+					let before = cpu.regs;
+					let ok = step_instruction(&mut cpu, &mut gui);
+					print_changes(&before, &cpu.regs);
+					if !ok {
+						break
+					}
+				}
+			},
+			"n" | "next" => { //step over calls
+				let pc = cpu.regs.pc;
+				let bytes = cpu.fetch(pc);
+				let insn = cpu.decode(bytes);
+				match insn.itype {
+					InstructionType::call | InstructionType::rst => {
+						//the call returns to the next instruction with the stack where it is now
+						let return_addr = pc.wrapping_add(insn.length);
+						let sp = cpu.regs.sp;
+						run_until(&mut cpu, &mut gui, &breakpoints, |cpu, _| cpu.regs.pc == return_addr && cpu.regs.sp == sp);
+					},
+					_ => {
+						step_instruction(&mut cpu, &mut gui);
+					}
+				}
+				let pc = BankAddr::current(&cpu.sys, cpu.regs.pc);
+				print_insn(&mut cpu, pc);
+			},
+			"finish" => { //run until the current function returns
+				let depth = cpu.call_stack.depth();
+				let sp = cpu.regs.sp;
+				let returned = run_until(&mut cpu, &mut gui, &breakpoints, |cpu, insn| {
+					if depth > 0 {
+						cpu.call_stack.depth() < depth
+					} else {
+						//not called while tracked, its return address is the first thing above the stack pointer
+						(insn.itype == InstructionType::ret || insn.itype == InstructionType::reti) && cpu.regs.sp > sp
+					}
+				});
+				if returned {
+					let pc = BankAddr::current(&cpu.sys, cpu.regs.pc);
+					print!("returned to ");
+					print_insn(&mut cpu, pc);
+				}
+			},
+			"history" => {
+				let n = match extract_opt_arg!(tokens, 1).map(|s| usize::from_str_radix(s, 10)) {
+					Some(Ok(n)) => n,
+					Some(Err(e)) => {
+						println!("Parse error: {}", e);
+						continue
+					},
+					None => 20
+				};
+				if let Some(ref history) = cpu.history {
+					for step in history.last(n) {
+						println!("{}", step);
+					}
+				}
+			},
+			"rs" | "reverse-step" => {
+				if gui.recording() {
+					continue
+				}
+				let n = match extract_opt_arg!(tokens, 1).map(|s| u32::from_str_radix(s, 10)) {
+					Some(Ok(n)) => n,
+					Some(Err(e)) => {
+						println!("Parse error: {}", e);
+						continue
+					},
+					None => 1
+				};
+				for _ in 0..n {
+					match cpu.reverse_step() {
+						Some((_, skipped)) => for w in skipped {
+							println!("couldn't undo write of {:>02x} to {:>04x} (was {:>02x})", w.new, w.addr, w.old);
+						},
+						None => {
+							println!("no more history");
+							break
+						}
+					}
+				}
+				//restoring memory isn't something to stop for
+				cpu.sys.watch_hits.clear();
+				let pc = BankAddr::current(&cpu.sys, cpu.regs.pc);
+				print_insn(&mut cpu, pc);
+			},
+			"bt" | "backtrace" => {
+				let pc = BankAddr::current(&cpu.sys, cpu.regs.pc);
+				println!("#0  {}", describe(&cpu, pc));
+				for (i, frame) in cpu.call_stack.frames.iter().rev().enumerate() {
+					let called = match frame.kind {
+						CallKind::Interrupt => format!("interrupt {:>04x}", frame.target),
+						_ => format!("{} {}", if frame.kind == CallKind::Rst { "rst" } else { "call" }, describe(&cpu, frame.target_addr()))
+					};
+					println!("#{:<2} {}  ({}, returns to {:>04x}, stack {:>04x})", i + 1, describe(&cpu, frame.site_addr()), called, frame.return_addr, frame.sp);
+				}
+			},
 			"load" => {
 				
 				let filename = extract_arg!(tokens, 1, "filename");
@@ -139,27 +431,125 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 			            continue
 			        }
 			    };
-				*system = Arc::new(RefCell::new(GBSystem::new(rom)));
+				//the cable stays plugged in, like the watchpoints stay set
+				let watchpoints = cpu.sys.watchpoints.clone();
+				let link = cpu.sys.take_serial_link();
+				cpu.sys = GBSystem::new(rom);
+				cpu.sys.watchpoints = watchpoints;
+				cpu.sys.set_serial_link(link);
 				cpu.reset();
+				cpu.symbols = Symbols::new();
+				if let Some(result) = Symbols::load_for_rom(filename) {
+					match result {
+						Ok((sym_filename, symbols)) => {
+							println!("loaded {} symbols from {}", symbols.len(), sym_filename);
+							cpu.symbols = symbols;
+						},
+						Err(e) => println!("Error: {}", e)
+					}
+				}
+			},
+			"symbols" => {
+				let filename = extract_arg!(tokens, 1, "filename");
+				match Symbols::load(filename) {
+					Ok(symbols) => {
+						println!("loaded {} symbols from {}", symbols.len(), filename);
+						cpu.symbols = symbols;
+					},
+					Err(e) => println!("Error: {}", e)
+				}
+			},
+			"savestate" | "loadstate" => {
+				//slot number or file name
+				let filename = match extract_opt_arg!(tokens, 1) {
+					Some(arg) => match u8::from_str_radix(arg, 10) {
+						Ok(slot) if slot < 10 => savestate::slot_filename(&cpu.sys.mbc.rom.filename, slot),
+						_ => arg.to_string()
+					},
+					None => savestate::slot_filename(&cpu.sys.mbc.rom.filename, 0)
+				};
+				let result = if tokens[0] == "savestate" {
+					savestate::save_to_file(&cpu, &filename)
+				} else if gui.recording() {
+					continue
+				} else {
+					savestate::load_from_file(&mut cpu, &filename)
+				};
+				match result {
+					Ok(_) => println!("{} {}", if tokens[0] == "savestate" { "saved state to" } else { "loaded state from" }, filename),
+					Err(e) => println!("Error: {}", e)
+				}
+			},
+			"record" => {
+				//records from the current state, which is embedded in the movie
+				let filename = extract_arg!(tokens, 1, "filename or stop");
+				if filename == "stop" {
+					gui.set_input(Box::new(LiveInput));
+					println!("stopped recording");
+					continue
+				}
+				match MovieRecorder::create_from_state(filename, &mut cpu) {
+					Ok(recorder) => {
+						gui.set_input(Box::new(recorder));
+						println!("recording to {}", filename)
+					},
+					Err(e) => println!("Error: {}", e)
+				}
+			},
+			"play" => {
+				let filename = extract_arg!(tokens, 1, "filename");
+				match MoviePlayer::open(filename, &mut cpu) {
+					Ok(player) => {
+						gui.set_input(Box::new(player));
+						println!("playing {}", filename)
+					},
+					Err(e) => println!("Error: {}", e)
+				}
+			},
+			"screenshot" => {
+				//file name and scale are optional
+				let scale = match extract_opt_arg!(tokens, 2) {
+					Some(s) => match usize::from_str_radix(s, 10) {
+						Ok(n) if n > 0 => n,
+						_ => {
+							println!("Invalid scale");
+							continue
+						}
+					},
+					None => 1
+				};
+				match extract_opt_arg!(tokens, 1) {
+					Some(filename) => match image::write_screenshot(filename, &cpu.sys.video.back_buffer[..], scale) {
+						Ok(_) => println!("saved screenshot {}", filename),
+						Err(e) => println!("Error: {}", e)
+					},
+					None => gui::screenshot(&cpu, scale)
+				}
 			},
 			"reset" => cpu.reset(),
 			"p" | "print" => {
 				if let Some(what) = extract_opt_arg!(tokens, 1) {
-					//try address first
-					match u16::from_str_radix(&what, 16) {
-						Ok(addr) => {
-							println!("({:>04x}) = {:>02x}", addr, system.borrow_mut().read8(addr));
-							continue
-						} 
-						Err(e) => {}
-					}
-					//now try regs
+					//registers first, they look like hex numbers
+					let single = tokens.len() == 2;
 					match what {
-						"af" | "bc" | "de" | "hl" | "sp" | "pc" => println!("{} = {:>04x}", what, cpu.regs.get16(Reg16Operand::from_str(what))),
-						"a" | "b" | "c" | "d" | "e" | "h" | "l" => println!("{} = {:>02x}",what, cpu.regs.get8(Reg8Operand::from_str(what))),
-						"f" | "flags" => println!("Z={}, N={}, H={}, C={}", cpu.regs.z_flag() as u8, cpu.regs.n_flag() as u8, cpu.regs.h_flag() as u8, cpu.regs.c_flag() as u8), 
-						"ime" => println!("IME = {}", cpu.regs.ime as u8),
-						_ => println!("Invalid print operand")
+						"af" | "bc" | "de" | "hl" | "sp" | "pc" if single => println!("{} = {:>04x}", what, cpu.regs.get16(Reg16Operand::from_str(what))),
+						"a" | "b" | "c" | "d" | "e" | "h" | "l" if single => println!("{} = {:>02x}",what, cpu.regs.get8(Reg8Operand::from_str(what))),
+						"f" | "flags" if single => println!("Z={}, N={}, H={}, C={}", cpu.regs.z_flag() as u8, cpu.regs.n_flag() as u8, cpu.regs.h_flag() as u8, cpu.regs.c_flag() as u8), 
+						"ime" if single => println!("IME = {}", cpu.regs.ime as u8),
+						_ => {
+							//a plain address shows the memory there
+							if single {
+								if let Ok(addr) = parse_addr(&cpu, what) {
+									println!("({}) = {:>02x}", addr, addr.read(&mut cpu.sys));
+									continue
+								}
+							}
+							let text = tokens[1..].join(" ");
+							match expr::parse(&text, &cpu.symbols).and_then(|e| e.eval(&mut cpu)) {
+								Ok(v) => println!("{} = {:x} ({})", text, v, v),
+								Err(e) => println!("Error: {}", e)
+							}
+						}
 					}
 				} else {
 					cpu.regs.dump()
@@ -178,31 +568,28 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 					None
 				};
 
-				loop {
-					if let Some(n) = max_insns {
-						if n == 0 {
-							break;
-						}
-						max_insns = Some(n-1)
-					}
-					
-					cpu.run_instruction();
-					gui.update(&mut cpu);
-					
-					if breakpoints.contains(&cpu.regs.pc) {
-						println!("Breakpoint triggered at {:>04x}", cpu.regs.pc); 
-						break;
-					}
-					
-					if gui.break_request {
-						break;
+				run_until(&mut cpu, &mut gui, &breakpoints, |_, _| {
+					match max_insns {
+						Some(n) => {
+							max_insns = Some(n-1);
+							n == 1
+						},
+						None => false
 					}
-				}
+				});
 			}, 
 			"i" | "info" => {
-				system.borrow().mbc.rom.dump_header();
+				cpu.sys.mbc.rom.dump_header();
 			}, //TODO print ROM info
 			"h" | "?" | "help" => {
+				println!("Addresses and values are hex, counts are decimal. Addresses in switchable ROM can be");
+				println!("given as BANK:ADDR, without a bank they mean the bank mapped now. Labels from a symbol");
+				println!("file can be used wherever an address is expected.");
+				println!("Expressions know the registers, ime, zf/nf/hf/cf, bank, ly, [ADDR] for memory, parentheses");
+				println!("and the C operators. Prefix numbers with 0x where they look like a name, e.g. 0xa.");
+				for &(syntax, description) in &HELP {
+					println!("  {:<38} {}", syntax, description);
+				}
 			}
 			"q" | "quit" | "exit" => break,
 			"" => {}, //ignore whitespace 
@@ -211,4 +598,135 @@ pub fn show(mut cpu : CPU, mut system : Arc<RefCell<GBSystem>>, mut gui : GUI) {
 		}
 		
 	}
-}
\ No newline at end of file
+}
+
+struct Breakpoint {
+	addr : BankAddr,
+	//and the text it was parsed from
+	condition : Option<(Expr, String)>
+}
+
+//run one instruction and update the screen. Returns false if the CPU faulted or was locked up already
+//or a watchpoint was triggered.
+fn step_instruction(cpu : &mut CPU, gui : &mut GUI) -> bool {
+	if cpu.locked_up {
+		println!("CPU is locked up at {:>04x}, reset to continue", cpu.regs.pc);
+		return false
+	}
+	let pc = cpu.regs.pc;
+	let result = cpu.run_instruction();
+	let mut ok = true;
+	for hit in cpu.sys.watch_hits.drain(..) {
+		if hit.write {
+			println!("Watchpoint {} triggered at {:>04x}: ({:>04x}) {:>02x} -> {:>02x}", hit.index, pc, hit.addr, hit.old, hit.new);
+		} else {
+			println!("Watchpoint {} triggered at {:>04x}: ({:>04x}) read {:>02x}", hit.index, pc, hit.addr, hit.new);
+		}
+		ok = false;
+	}
+	//just a warning, some code returns through the stack on purpose
+	for problem in cpu.call_stack.problems.drain(..) {
+		println!("Call stack: {}", problem);
+	}
+	if let Err(e) = result {
+		//stop at the faulting instruction so it can be inspected
+		println!("{}", e);
+		let pc = BankAddr::current(&cpu.sys, e.pc());
+		print_insn(cpu, pc);
+		return false
+	}
+	gui.update(cpu);
+	ok
+}
+
+//run until `done` returns true, a breakpoint is reached, the user hits the break key or the CPU faults.
+//`done` gets the CPU after each instruction and the instruction that was executed.
+//Returns true if `done` ended the run.
+fn run_until<F>(cpu : &mut CPU, gui : &mut GUI, breakpoints : &[Breakpoint], mut done : F) -> bool where F : FnMut(&CPU, &Instruction) -> bool {
+	loop {
+		let pc = cpu.regs.pc;
+		let bytes = cpu.fetch(pc);
+		let insn = cpu.decode(bytes);
+		if !step_instruction(cpu, gui) {
+			return false
+		}
+		if done(cpu, &insn) {
+			return true
+		}
+		let pc = cpu.regs.pc;
+		for b in breakpoints.iter().filter(|b| b.addr.addr == pc) {
+			if !b.addr.is_mapped(&cpu.sys) {
+				continue
+			}
+			let hit = match b.condition {
+				Some((ref e, ref text)) => match e.eval(cpu) {
+					Ok(v) => v != 0,
+					Err(e) => {
+						println!("Couldn't evaluate {}: {}", text, e);
+						true
+					}
+				},
+				None => true
+			};
+			if hit {
+				println!("Breakpoint triggered at {}", b.addr);
+				return false
+			}
+		}
+		if gui.break_request {
+			return false
+		}
+	}
+}
+
+//registers that differ after a step, PC is shown by the next instruction
+fn print_changes(before : &GBRegisters, after : &GBRegisters) {
+	let mut changes = Vec::new();
+	for &(name, old, new) in &[("a", before.af >> 8, after.af >> 8), ("f", before.af & 0xff, after.af & 0xff),
+							   ("b", before.bc >> 8, after.bc >> 8), ("c", before.bc & 0xff, after.bc & 0xff),
+							   ("d", before.de >> 8, after.de >> 8), ("e", before.de & 0xff, after.de & 0xff),
+							   ("h", before.hl >> 8, after.hl >> 8), ("l", before.hl & 0xff, after.hl & 0xff)] {
+		if old != new {
+			changes.push(format!("{}={:>02x}->{:>02x}", name, old, new));
+		}
+	}
+	if before.sp != after.sp {
+		changes.push(format!("sp={:>04x}->{:>04x}", before.sp, after.sp));
+	}
+	if before.ime != after.ime {
+		changes.push(format!("ime={}->{}", before.ime as u8, after.ime as u8));
+	}
+	if !changes.is_empty() {
+		println!("      {}", changes.join(" "));
+	}
+}
+
+//disassemble the instruction at `addr` and return its length
+fn print_insn(cpu : &mut CPU, addr : BankAddr) -> u16 {
+	let bytes = [addr.read(&mut cpu.sys), addr.offset(1).read(&mut cpu.sys), addr.offset(2).read(&mut cpu.sys)];
+	let insn = cpu.decode(bytes);
+	if let Some(label) = cpu.symbols.name_at(addr) {
+		println!("{}:", label);
+	}
+	match cpu.symbols.operand_name(&insn, addr, cpu.sys.mbc.rom_bank() as u16) {
+		Some(label) => println!("{}: {:<16} ; {}", addr, format!("{}", insn), label),
+		None => println!("{}: {}", addr, insn)
+	}
+	insn.length
+}
+
+//an address with the label it's in, if any
+fn describe(cpu : &CPU, addr : BankAddr) -> String {
+	match cpu.symbols.describe(addr) {
+		Some(label) => format!("{} <{}>", addr, label),
+		None => format!("{}", addr)
+	}
+}
+
+//a label or bank:addr
+fn parse_addr(cpu : &CPU, text : &str) -> Result<BankAddr, String> {
+	match cpu.symbols.lookup(text) {
+		Some(addr) => Ok(addr),
+		None => BankAddr::parse(text)
+	}
+}
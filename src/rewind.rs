@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+
+use core::cpu::CPU;
+use savestate;
+
+//an older snapshot, relative to the one after it
+enum Snapshot {
+	Delta(Vec<u8>),
+	//the whole state, when its layout differs from the next one, e.g. after loading another ROM
+	Keyframe(Vec<u8>)
+}
+
+//ring buffer of machine snapshots. Only the newest snapshot is kept in full, older ones are
+//stored as the compressed XOR delta to their successor.
+pub struct RewindBuffer {
+	deltas : VecDeque<Snapshot>,
+	newest : Option<Vec<u8>>,
+	capacity : usize,
+	interval : u32,
+	frames : u32
+}
+
+impl RewindBuffer {
+
+	//keep `capacity` snapshots, one every `interval` frames
+	pub fn new(capacity : usize, interval : u32) -> RewindBuffer {
+		RewindBuffer {
+			deltas : VecDeque::with_capacity(capacity),
+			newest : None,
+			capacity : capacity,
+			interval : interval,
+			frames : 0
+		}
+	}
+
+	//call once per emulated frame
+	pub fn record_frame(&mut self, cpu : &CPU) {
+		self.frames += 1;
+		if self.frames < self.interval {
+			return
+		}
+		self.frames = 0;
+
+		let state = savestate::save(cpu);
+		if let Some(previous) = self.newest.take() {
+			let snapshot = if previous.len() == state.len() {
+				let delta : Vec<u8> = previous.iter().zip(state.iter()).map(|(a, b)| a ^ b).collect();
+				Snapshot::Delta(compress(&delta))
+			} else {
+				println!("Save state size changed from {} to {} bytes, keeping a full rewind snapshot", previous.len(), state.len());
+				Snapshot::Keyframe(previous)
+			};
+			self.deltas.push_back(snapshot);
+			if self.deltas.len() > self.capacity {
+				self.deltas.pop_front();
+			}
+		}
+		self.newest = Some(state);
+	}
+
+	//go back one snapshot. Returns false if there is nothing left to rewind.
+	pub fn rewind(&mut self, cpu : &mut CPU) -> bool {
+		self.frames = 0;
+		let mut state = match self.newest.take() {
+			Some(s) => s,
+			None => return false
+		};
+		let stepped = match self.deltas.pop_back() {
+			Some(Snapshot::Delta(delta)) => {
+				decompress_xor(&delta, &mut state);
+				true
+			},
+			Some(Snapshot::Keyframe(keyframe)) => {
+				state = keyframe;
+				true
+			},
+			None => false
+		};
+		if let Err(e) = savestate::load(cpu, &state) {
+			println!("Couldn't rewind: {}", e);
+			self.clear();
+			return false
+		}
+		self.newest = Some(state);
+		stepped
+	}
+
+	pub fn clear(&mut self) {
+		self.deltas.clear();
+		self.newest = None;
+		self.frames = 0;
+	}
+}
+
+//deltas are mostly zero. Encode them as (zero run, literal count, literals)* with 16 bit lengths.
+fn compress(delta : &[u8]) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut i = 0;
+	while i < delta.len() {
+		let zeros_start = i;
+		while i < delta.len() && delta[i] == 0 && i - zeros_start < 0xffff {
+			i += 1;
+		}
+		let zeros = i - zeros_start;
+
+		let literals_start = i;
+		while i < delta.len() && delta[i] != 0 && i - literals_start < 0xffff {
+			i += 1;
+		}
+		let literals = i - literals_start;
+
+		out.extend_from_slice(&[zeros as u8, (zeros >> 8) as u8, literals as u8, (literals >> 8) as u8]);
+		out.extend_from_slice(&delta[literals_start..i]);
+	}
+	out
+}
+
+//apply a compressed delta to `state` in place
+fn decompress_xor(compressed : &[u8], state : &mut [u8]) {
+	let (mut i, mut pos) = (0, 0);
+	while i + 4 <= compressed.len() {
+		let zeros = (compressed[i] as usize) | ((compressed[i+1] as usize) << 8);
+		let literals = (compressed[i+2] as usize) | ((compressed[i+3] as usize) << 8);
+		i += 4;
+		pos += zeros;
+		for b in &compressed[i..i + literals] {
+			state[pos] ^= *b;
+			pos += 1;
+		}
+		i += literals;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{compress, decompress_xor};
+
+	fn round_trip(old : &[u8], new : &[u8]) {
+		let delta : Vec<u8> = old.iter().zip(new.iter()).map(|(a, b)| a ^ b).collect();
+		let mut state = new.to_vec();
+		decompress_xor(&compress(&delta), &mut state);
+		assert_eq!(&state[..], old);
+	}
+
+	#[test]
+	fn compress_xor_round_trip() {
+		round_trip(&[], &[]);
+		round_trip(&[1, 2, 3], &[1, 2, 3]);
+		round_trip(&[0, 0, 5, 0, 7, 7, 0], &[1, 0, 5, 9, 7, 0, 0]);
+	}
+
+	#[test]
+	fn compress_xor_long_runs() {
+		//runs longer than the 16 bit lengths
+		let old : Vec<u8> = (0..200000).map(|i| if i % 70001 < 66000 { 0 } else { (i % 251) as u8 }).collect();
+		let new : Vec<u8> = (0..200000).map(|i| if i < 100000 { 0 } else { (i % 13) as u8 }).collect();
+		round_trip(&old, &new);
+		round_trip(&new, &old);
+	}
+}
@@ -5,6 +5,7 @@ use std::io::Read;
 use std::io;
 use std::str;
 use std::mem;
+use std::path::Path;
 
 
 use self::header::*;
@@ -14,6 +15,11 @@ use self::header::CartridgeType::*;
 pub const NUM_ROM_BANK_BYTES : usize = 16384;
 pub type RomBank = Box<[u8; NUM_ROM_BANK_BYTES]>;
 
+//a file next to the ROM, like its .sav. An empty extension gives the name without one.
+pub fn path_with_extension(rom_filename : &str, extension : &str) -> String {
+	Path::new(rom_filename).with_extension(extension).to_string_lossy().into_owned()
+}
+
 pub struct Rom {
 	pub filename : String,
     pub banks : Vec<RomBank>,
@@ -43,6 +49,10 @@ impl Rom {
     pub fn has_battery(&self) -> bool {
     	self.battery
     }
+    
+    pub fn global_checksum(&self) -> u16 {
+    	((self.banks[0][0x14e] as u16) << 8) | (self.banks[0][0x14f] as u16)
+    }
 
     pub fn create_from_file(filename : &str) -> Result<Rom, io::Error> {
     	
@@ -97,8 +107,8 @@ impl Rom {
         		filename : filename.to_string(),
         		banks : banks,
         		title : title.to_string(),
-        		cgb_flag : true,
-        		sgb_flag : true,
+        		cgb_flag : data[0x143] & 0x80 != 0,
+        		sgb_flag : data[0x146] == 0x03,
         		rom_type : rom_type,
         		rom_size : rom_size,
         		rom_manufacturer : rom_manufacturer,
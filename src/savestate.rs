@@ -0,0 +1,262 @@
+use std::io::{self, Read, Write};
+use std::fs::File;
+
+use core::cpu::{self, CPU};
+use rom::{self, Rom};
+
+const MAGIC : &'static [u8; 4] = b"RBSS";
+
+//bump this whenever the layout changes. Loaders check `StateReader::version`
+//to read states written by older versions.
+pub const VERSION : u16 = 5;
+
+pub trait SaveState {
+	fn save_state(&self, w : &mut StateWriter);
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()>;
+}
+
+pub struct StateWriter {
+	buf : Vec<u8>
+}
+
+impl StateWriter {
+
+	pub fn new() -> StateWriter {
+		StateWriter { buf : Vec::new() }
+	}
+
+	pub fn write_u8(&mut self, v : u8) {
+		self.buf.push(v);
+	}
+
+	pub fn write_bool(&mut self, v : bool) {
+		self.buf.push(v as u8);
+	}
+
+	pub fn write_u16(&mut self, v : u16) {
+		self.buf.push(v as u8);
+		self.buf.push((v >> 8) as u8);
+	}
+
+	pub fn write_u32(&mut self, v : u32) {
+		self.write_u16(v as u16);
+		self.write_u16((v >> 16) as u16);
+	}
+
+	pub fn write_u64(&mut self, v : u64) {
+		self.write_u32(v as u32);
+		self.write_u32((v >> 32) as u32);
+	}
+
+	pub fn write_bytes(&mut self, v : &[u8]) {
+		self.buf.extend_from_slice(v);
+	}
+
+	pub fn into_inner(self) -> Vec<u8> {
+		self.buf
+	}
+}
+
+pub struct StateReader<'a> {
+	data : &'a [u8],
+	pos : usize,
+	pub version : u16
+}
+
+impl<'a> StateReader<'a> {
+
+	pub fn new(data : &'a [u8]) -> StateReader<'a> {
+		StateReader { data : data, pos : 0, version : VERSION }
+	}
+
+	pub fn read_u8(&mut self) -> io::Result<u8> {
+		if self.pos >= self.data.len() {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "save state is truncated"))
+		}
+		self.pos += 1;
+		Ok(self.data[self.pos - 1])
+	}
+
+	pub fn read_bool(&mut self) -> io::Result<bool> {
+		Ok(try!(self.read_u8()) != 0)
+	}
+
+	pub fn read_u16(&mut self) -> io::Result<u16> {
+		let low = try!(self.read_u8()) as u16;
+		let high = try!(self.read_u8()) as u16;
+		Ok((high << 8) | low)
+	}
+
+	pub fn read_u32(&mut self) -> io::Result<u32> {
+		let low = try!(self.read_u16()) as u32;
+		let high = try!(self.read_u16()) as u32;
+		Ok((high << 16) | low)
+	}
+
+	pub fn read_u64(&mut self) -> io::Result<u64> {
+		let low = try!(self.read_u32()) as u64;
+		let high = try!(self.read_u32()) as u64;
+		Ok((high << 32) | low)
+	}
+
+	pub fn read_bytes(&mut self, buf : &mut [u8]) -> io::Result<()> {
+		if self.pos + buf.len() > self.data.len() {
+			return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "save state is truncated"))
+		}
+		buf.copy_from_slice(&self.data[self.pos..self.pos + buf.len()]);
+		self.pos += buf.len();
+		Ok(())
+	}
+}
+
+fn invalid_data(msg : &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_rom_id(w : &mut StateWriter, rom : &Rom) {
+	w.write_u16(rom.global_checksum());
+	w.write_u8(rom.title.len() as u8);
+	w.write_bytes(rom.title.as_bytes());
+}
+
+fn check_rom_id(r : &mut StateReader, rom : &Rom) -> io::Result<()> {
+	let checksum = try!(r.read_u16());
+	let mut title = vec![0; try!(r.read_u8()) as usize];
+	try!(r.read_bytes(&mut title));
+	if checksum != rom.global_checksum() || title != rom.title.as_bytes() {
+		return Err(invalid_data("save state belongs to a different ROM"))
+	}
+	Ok(())
+}
+
+//snapshot the whole machine
+pub fn save(cpu : &CPU) -> Vec<u8> {
+	let sys = &cpu.sys;
+	let mut w = StateWriter::new();
+	w.write_bytes(MAGIC);
+	w.write_u16(VERSION);
+	write_rom_id(&mut w, &sys.mbc.rom);
+	cpu.save_state(&mut w);
+	sys.save_state(&mut w);
+	w.into_inner()
+}
+
+//restore a snapshot taken by save(). The machine is left untouched if the state can't be loaded.
+pub fn load(cpu : &mut CPU, data : &[u8]) -> io::Result<()> {
+	let backup = save(cpu);
+	match load_unchecked(cpu, data) {
+		Ok(_) => Ok(()),
+		Err(e) => match load_unchecked(cpu, &backup) {
+			Ok(_) => Err(e),
+			Err(restore_error) => Err(io::Error::new(io::ErrorKind::Other,
+				format!("{}, restoring the previous state failed too: {}", e, restore_error)))
+		}
+	}
+}
+
+fn load_unchecked(cpu : &mut CPU, data : &[u8]) -> io::Result<()> {
+	let mut r = StateReader::new(data);
+	let mut magic = [0; 4];
+	try!(r.read_bytes(&mut magic));
+	if &magic != MAGIC {
+		return Err(invalid_data("not a rustyboy save state"))
+	}
+	r.version = try!(r.read_u16());
+	if r.version > VERSION {
+		return Err(invalid_data("save state was written by a newer version"))
+	}
+	try!(check_rom_id(&mut r, &cpu.sys.mbc.rom));
+	try!(cpu.load_state(&mut r));
+	try!(cpu.sys.load_state(&mut r));
+	cpu.icache.clear();
+	cpu.clk_period_ns = cpu::clk_period_ns(cpu.sys.double_speed);
+	Ok(())
+}
+
+pub fn save_to_file(cpu : &CPU, filename : &str) -> io::Result<()> {
+	let mut f = try!(File::create(filename));
+	f.write_all(&save(cpu))
+}
+
+pub fn load_from_file(cpu : &mut CPU, filename : &str) -> io::Result<()> {
+	let mut data = Vec::new();
+	try!(try!(File::open(filename)).read_to_end(&mut data));
+	load(cpu, &data)
+}
+
+//file name of a numbered save slot next to the ROM
+pub fn slot_filename(rom_filename : &str, slot : u8) -> String {
+	rom::path_with_extension(rom_filename, &format!("ss{}", slot))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use input::{BUTTON_A, BUTTON_LEFT};
+	use system;
+
+	fn test_machine() -> CPU {
+		let rom = Rom::create_from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms/serial.gb")).unwrap();
+		system::init(rom)
+	}
+
+	#[test]
+	fn reader_writer_round_trip() {
+		let mut w = StateWriter::new();
+		w.write_u8(0x12);
+		w.write_bool(true);
+		w.write_u16(0x3456);
+		w.write_u32(0x789abcde);
+		w.write_u64(0x0123456789abcdef);
+		w.write_bytes(&[1, 2, 3]);
+		let data = w.into_inner();
+		//little endian
+		assert_eq!(&data[2..4], &[0x56, 0x34]);
+
+		let mut r = StateReader::new(&data);
+		assert_eq!(r.read_u8().unwrap(), 0x12);
+		assert_eq!(r.read_bool().unwrap(), true);
+		assert_eq!(r.read_u16().unwrap(), 0x3456);
+		assert_eq!(r.read_u32().unwrap(), 0x789abcde);
+		assert_eq!(r.read_u64().unwrap(), 0x0123456789abcdef);
+		let mut bytes = [0; 3];
+		r.read_bytes(&mut bytes).unwrap();
+		assert_eq!(bytes, [1, 2, 3]);
+		assert_eq!(r.read_u8().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+		assert!(r.read_bytes(&mut bytes).is_err());
+	}
+
+	#[test]
+	fn machine_round_trip() {
+		let mut cpu = test_machine();
+		for _ in 0..1000 {
+			cpu.run_instruction().unwrap();
+		}
+		cpu.sys.joypad.set_buttons(BUTTON_A | BUTTON_LEFT);
+		let state = save(&cpu);
+
+		let mut other = test_machine();
+		load(&mut other, &state).unwrap();
+		assert_eq!(other.sys.joypad.buttons(), BUTTON_A | BUTTON_LEFT);
+		assert_eq!(save(&other), state);
+
+		//and both run on the same way
+		for _ in 0..1000 {
+			cpu.run_instruction().unwrap();
+			other.run_instruction().unwrap();
+		}
+		assert_eq!(save(&other), save(&cpu));
+	}
+
+	#[test]
+	fn failed_load_keeps_the_machine() {
+		let mut cpu = test_machine();
+		let before = save(&cpu);
+		assert!(load(&mut cpu, &before[..before.len() - 1]).is_err());
+		assert!(load(&mut cpu, b"RBSS").is_err());
+		let mut newer = before.clone();
+		newer[4] = (VERSION + 1) as u8;
+		assert!(load(&mut cpu, &newer).is_err());
+		assert_eq!(save(&cpu), before);
+	}
+}
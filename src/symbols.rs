@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use address::{self, BankAddr};
+use rom;
+use core::instruction::{Instruction, InstructionType};
+use core::operands::Operand;
+
+//labels from a symbol file. RGBDS and no$gmb both write "bank:addr name" lines in hex,
+//no$gmb adds [section] headers. Comments start with ';'.
+pub struct Symbols {
+	addrs : HashMap<String, BankAddr>,
+	names : HashMap<BankAddr, String>
+}
+
+impl Symbols {
+
+	pub fn new() -> Symbols {
+		Symbols {
+			addrs : HashMap::new(),
+			names : HashMap::new()
+		}
+	}
+
+	pub fn load(filename : &str) -> io::Result<Symbols> {
+		let mut text = String::new();
+		try!(try!(File::open(filename)).read_to_string(&mut text));
+		Symbols::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", filename, e)))
+	}
+
+	//the .sym file next to a ROM, if there is one
+	pub fn load_for_rom(rom_filename : &str) -> Option<io::Result<(String, Symbols)>> {
+		let filename = rom::path_with_extension(rom_filename, "sym");
+		if !Path::new(&filename).exists() {
+			return None
+		}
+		Some(Symbols::load(&filename).map(|s| (filename, s)))
+	}
+
+	pub fn parse(text : &str) -> Result<Symbols, String> {
+		let mut symbols = Symbols::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = line.splitn(2, ';').next().unwrap().trim();
+			if line.is_empty() || line.starts_with('[') {
+				continue
+			}
+			let mut fields = line.split_whitespace();
+			let (location, name) = match (fields.next(), fields.next()) {
+				(Some(l), Some(n)) => (l, n),
+				_ => return Err(format!("line {}: expected bank:addr name", i + 1))
+			};
+			let mut parts = location.splitn(2, ':');
+			let (bank, addr) = match (parts.next().map(|b| u16::from_str_radix(b, 16)), parts.next().map(|a| u16::from_str_radix(a, 16))) {
+				(Some(Ok(b)), Some(Ok(a))) => (b, a),
+				_ => return Err(format!("line {}: invalid address {}", i + 1, location))
+			};
+			//banks of RAM aren't distinguished by the debugger
+			let addr = BankAddr { bank : if address::is_banked(addr) { Some(bank) } else { None }, addr : addr };
+			symbols.add(name, addr);
+		}
+		Ok(symbols)
+	}
+
+	//the first label at an address is the one shown
+	pub fn add(&mut self, name : &str, addr : BankAddr) {
+		self.addrs.insert(name.to_string(), addr);
+		if !self.names.contains_key(&addr) {
+			self.names.insert(addr, name.to_string());
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.addrs.len()
+	}
+
+	pub fn lookup(&self, name : &str) -> Option<BankAddr> {
+		self.addrs.get(name).cloned()
+	}
+
+	pub fn name_at(&self, addr : BankAddr) -> Option<&str> {
+		self.names.get(&addr).map(|s| &s[..])
+	}
+
+	//the closest label at or before `addr` in the same bank and 16K area, plus an offset
+	pub fn describe(&self, addr : BankAddr) -> Option<String> {
+		self.names.iter()
+			.filter(|&(a, _)| a.bank == addr.bank && a.addr <= addr.addr && (a.addr ^ addr.addr) < 0x4000)
+			.max_by_key(|&(a, _)| a.addr)
+			.map(|(a, name)| if a.addr == addr.addr { name.clone() } else { format!("{}+{:x}", name, addr.addr - a.addr) })
+	}
+
+	//the label of the address an instruction at `at` jumps to or accesses. Switchable ROM
+	//means the bank of the instruction itself if it is there, otherwise `mapped_bank`.
+	pub fn operand_name(&self, insn : &Instruction, at : BankAddr, mapped_bank : u16) -> Option<&str> {
+		if self.names.is_empty() {
+			return None
+		}
+		let target = match (insn.itype, insn.dest, insn.src[0]) {
+			(InstructionType::jr, _, Operand::imm8(off)) => at.addr.wrapping_add(insn.length).wrapping_add(off as i8 as u16),
+			(InstructionType::jp, _, Operand::imm16(a)) | (InstructionType::call, _, Operand::imm16(a)) => a,
+			(_, Operand::mem_imm(a), _) | (_, _, Operand::mem_imm(a)) => a,
+			_ => return None
+		};
+		let bank = if !address::is_banked(target) {
+			None
+		} else if address::is_banked(at.addr) {
+			at.bank
+		} else {
+			Some(mapped_bank)
+		};
+		self.name_at(BankAddr { bank : bank, addr : target })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use address::BankAddr;
+	use super::Symbols;
+
+	#[test]
+	fn parse() {
+		let text = "; File created by rgblink\n\
+			[labels]\n\
+			00:0150 Main\n\
+			00:0158 Main.loop ; a local label\n\
+			\n\
+			01:4010 Hello\n\
+			02:4010 Hello2\n\
+			00:C000 wPlayerX\n\
+			03:d000 wBank3\n\
+			01:4010 HelloAlias\n";
+		let symbols = Symbols::parse(text).unwrap();
+		assert_eq!(symbols.len(), 7);
+		assert_eq!(symbols.lookup("Main"), Some(BankAddr::new(0x150)));
+		assert_eq!(symbols.lookup("Main.loop"), Some(BankAddr::new(0x158)));
+		assert_eq!(symbols.lookup("Hello"), Some(BankAddr { bank : Some(1), addr : 0x4010 }));
+		assert_eq!(symbols.lookup("Hello2"), Some(BankAddr { bank : Some(2), addr : 0x4010 }));
+		//only switchable ROM keeps its bank
+		assert_eq!(symbols.lookup("wPlayerX"), Some(BankAddr::new(0xc000)));
+		assert_eq!(symbols.lookup("wBank3"), Some(BankAddr::new(0xd000)));
+		assert_eq!(symbols.lookup("labels"), None);
+		//the first name at an address is shown
+		assert_eq!(symbols.name_at(BankAddr { bank : Some(1), addr : 0x4010 }), Some("Hello"));
+		assert_eq!(symbols.lookup("HelloAlias"), Some(BankAddr { bank : Some(1), addr : 0x4010 }));
+		assert_eq!(symbols.describe(BankAddr::new(0x15a)), Some("Main.loop+2".to_string()));
+		assert_eq!(symbols.describe(BankAddr { bank : Some(2), addr : 0x4011 }), Some("Hello2+1".to_string()));
+	}
+
+	#[test]
+	fn bad_lines() {
+		assert_eq!(Symbols::parse("00:0150 Main\n0150 Start").err(), Some("line 2: invalid address 0150".to_string()));
+		assert_eq!(Symbols::parse("00:0150").err(), Some("line 1: expected bank:addr name".to_string()));
+		assert!(Symbols::parse("00:xyz Main").is_err());
+		assert!(Symbols::parse("00:10000 Main").is_err());
+		assert!(Symbols::parse("; only a comment\n").unwrap().len() == 0);
+	}
+}
@@ -1,4 +1,6 @@
+use std::io;
 use super::ioregister::IORegister;
+use savestate::{SaveState, StateWriter, StateReader};
 
 pub const INTERRUPT_VBLANK : u8 = 1 << 0;
 pub const INTERRUPT_LCD_STAT : u8 = 1 << 1;
@@ -11,4 +13,17 @@ pub const INTERRUPT_JOYPAD : u8  = 1 << 4;
 pub struct InterruptRegisters {
 	pub ienable : IORegister,
 	pub iflags : IORegister
-}
\ No newline at end of file
+}
+
+impl SaveState for InterruptRegisters {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		self.ienable.save_state(w);
+		self.iflags.save_state(w);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		try!(self.ienable.load_state(r));
+		self.iflags.load_state(r)
+	}
+}
@@ -1,6 +1,8 @@
 use std::ops::{Deref, DerefMut};
 use std::fmt;
+use std::io;
 use super::system::MemoryAccess;
+use savestate::{SaveState, StateWriter, StateReader};
 
 pub struct IORegister {
 	pub data : u8,
@@ -83,4 +85,16 @@ impl MemoryAccess for IORegister {
 	fn read(&mut self, _: u16) -> u8 {
 		self.data & self.rmask
 	}
-}
\ No newline at end of file
+}
+
+impl SaveState for IORegister {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_u8(self.data);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		self.data = try!(r.read_u8());
+		Ok(())
+	}
+}
@@ -1,6 +1,9 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
 use super::interrupt::InterruptRegisters;
+use savestate::{SaveState, StateWriter, StateReader};
+use input::*;
 
 bitflags! {
     flags SelectMask: u8 {
@@ -139,5 +142,44 @@ impl Joypad {
 		} else { 
 			self.dir_keys.remove(KEY_RIGHT)
 		}
-	}	
-}
\ No newline at end of file
+	}
+
+	pub fn buttons(&self) -> Buttons {
+		//key bits are active low
+		let btn = !self.btn_keys.bits() & 0xf;
+		let dir = !self.dir_keys.bits() & 0xf;
+		Buttons::from_bits_truncate(btn | (dir << 4))
+	}
+
+	//only touch keys that changed so we don't raise spurious interrupts
+	pub fn set_buttons(&mut self, buttons : Buttons) {
+		let changed = self.buttons() ^ buttons;
+		if changed.contains(BUTTON_A) { self.set_a_pressed(buttons.contains(BUTTON_A)) }
+		if changed.contains(BUTTON_B) { self.set_b_pressed(buttons.contains(BUTTON_B)) }
+		if changed.contains(BUTTON_SELECT) { self.set_select_pressed(buttons.contains(BUTTON_SELECT)) }
+		if changed.contains(BUTTON_START) { self.set_start_pressed(buttons.contains(BUTTON_START)) }
+		if changed.contains(BUTTON_RIGHT) { self.set_right_pressed(buttons.contains(BUTTON_RIGHT)) }
+		if changed.contains(BUTTON_LEFT) { self.set_left_pressed(buttons.contains(BUTTON_LEFT)) }
+		if changed.contains(BUTTON_UP) { self.set_up_pressed(buttons.contains(BUTTON_UP)) }
+		if changed.contains(BUTTON_DOWN) { self.set_down_pressed(buttons.contains(BUTTON_DOWN)) }
+	}
+}
+
+//held keys are saved too, P1 reads them
+impl SaveState for Joypad {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_u8(self.sel_mask.bits());
+		w.write_u8(self.dir_keys.bits());
+		w.write_u8(self.btn_keys.bits());
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		self.sel_mask = SelectMask::from_bits_truncate(try!(r.read_u8()));
+		if r.version >= 5 {
+			self.dir_keys = DirKeyMask::from_bits_truncate(try!(r.read_u8()));
+			self.btn_keys = ButtonKeyMask::from_bits_truncate(try!(r.read_u8()));
+		}
+		Ok(())
+	}
+}
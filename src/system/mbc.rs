@@ -4,7 +4,8 @@ use super::system::MemoryAccess;
 use std::cmp::max;
 use std::fs::{File,OpenOptions};
 use std::path::Path;
-use std::io::{Read,Write,Seek,SeekFrom};
+use std::io::{self,Read,Write,Seek,SeekFrom};
+use savestate::{SaveState, StateWriter, StateReader};
 
 const EXT_RAM_BANK_SIZE: usize = 8*1024;
 
@@ -77,14 +78,52 @@ impl MBC {
 		}
 	}
 	
+	pub fn rom_bank(&self) -> u8 {
+		self.rom_bank
+	}
+	
+	//for the debugger: a byte of any ROM bank, None if the bank doesn't exist
+	pub fn read_rom_bank(&self, bank : usize, addr : u16) -> Option<u8> {
+		self.rom.banks.get(bank).map(|b| b[(addr & 0x3fff) as usize])
+	}
+	
+	//change the ROM image, returns false if the bank doesn't exist
+	pub fn patch_rom(&mut self, bank : usize, addr : u16, data : u8) -> bool {
+		match self.rom.banks.get_mut(bank) {
+			Some(b) => {
+				b[(addr & 0x3fff) as usize] = data;
+				true
+			},
+			None => false
+		}
+	}
+	
+	//the cartridge RAM, including what was loaded from the battery save
+	pub fn ram(&self) -> &[u8] {
+		&self.ram
+	}
+	
+	//replace the cartridge RAM without touching the save file, returns false if the size differs
+	pub fn load_ram(&mut self, data : &[u8]) -> bool {
+		if data.len() != self.ram.len() {
+			return false
+		}
+		self.ram.copy_from_slice(data);
+		true
+	}
+	
 	#[inline(always)]
 	pub fn read(&mut self, addr: u16) -> u8 {
 		
 		match addr >> 8 {
 			0x00 ... 0x3f => self.rom.banks[0][addr as usize],
 			0x40 ... 0x7f => self.rom.banks[self.rom_bank as usize][(addr - 0x4000) as usize],
-			0xa0 ... 0xbf => if self.ram_enabled { self.ram[(self.ram_bank*EXT_RAM_BANK_SIZE + (addr - 0xa000) as usize)] } else { 0xff },
-			_ => unimplemented!()
+			0xa0 ... 0xbf => if self.ram_enabled {
+				//cartridges without RAM read open bus
+				let ix = self.ram_bank*EXT_RAM_BANK_SIZE + (addr - 0xa000) as usize;
+				self.ram.get(ix).cloned().unwrap_or(0xff)
+			} else { 0xff },
+			_ => 0xff //not mapped to the cartridge
 		}
 	}
 	
@@ -145,4 +184,27 @@ impl MBC {
 		}
 
 	}
-}
\ No newline at end of file
+}
+
+impl SaveState for MBC {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_u8(self.rom_bank);
+		w.write_u32(self.ram_bank as u32);
+		w.write_bool(self.ram_mode);
+		w.write_bool(self.ram_enabled);
+		w.write_u32(self.ram.len() as u32);
+		w.write_bytes(&self.ram);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		self.rom_bank = try!(r.read_u8());
+		self.ram_bank = try!(r.read_u32()) as usize;
+		self.ram_mode = try!(r.read_bool());
+		self.ram_enabled = try!(r.read_bool());
+		if try!(r.read_u32()) as usize != self.ram.len() {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "cartridge RAM size mismatch"))
+		}
+		r.read_bytes(&mut self.ram)
+	}
+}
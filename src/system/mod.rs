@@ -7,24 +7,22 @@ mod timer;
 mod mbc;
 mod interrupt;
 mod wram;
-mod serial;
+pub mod serial;
+pub mod tcplink;
+pub mod printer;
 mod joypad;
-
-use std::sync::Arc;
-use std::cell::RefCell;
+mod scheduler;
+pub mod watchpoint;
 
 use rom::Rom;
 use core::cpu::CPU;
 use self::system::*;
 
-pub fn init(rom: Rom) -> (CPU, Arc<RefCell<GBSystem>>) {
+pub fn init(rom: Rom) -> CPU {
 
 	//create CPU peripherals
-	let raw_sys = GBSystem::new(rom);
-	let sys = Arc::new(RefCell::new(raw_sys));
+	let sys = GBSystem::new(rom);
 	
 	//create CPU
-	let cpu = CPU::new(sys.clone());
-
-    (cpu, sys)
+	CPU::new(sys)
 }
\ No newline at end of file
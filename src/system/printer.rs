@@ -0,0 +1,339 @@
+use std::mem;
+use super::serial::SerialLink;
+use image;
+
+const CMD_INIT : u8 = 0x01;
+const CMD_PRINT : u8 = 0x02;
+const CMD_DATA : u8 = 0x04;
+const CMD_STATUS : u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR : u8 = 1<<0;
+const STATUS_BUSY : u8 = 1<<1;
+const STATUS_IMAGE_FULL : u8 = 1<<2;
+const STATUS_UNPROCESSED : u8 = 1<<3;
+
+const DEVICE_ID : u8 = 0x81;
+
+const PRINT_WIDTH : usize = 160;
+const TILES_PER_ROW : usize = PRINT_WIDTH/8;
+const BYTES_PER_TILE : usize = 16;
+const BAND_SIZE : usize = 2*TILES_PER_ROW*BYTES_PER_TILE; 	//one DATA packet holds 160x16 pixels
+const BUFFER_SIZE : usize = 9*BAND_SIZE;					//160x144 pixels
+const BUSY_POLLS : u32 = 2;									//status requests answered with "busy" after printing
+
+const SHADES : [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Copy,Clone,PartialEq)]
+enum PacketState {
+	Magic1,
+	Magic2,
+	Command,
+	Compression,
+	LengthLow,
+	LengthHigh,
+	Data,
+	ChecksumLow,
+	ChecksumHigh,
+	Ack,
+	Status
+}
+
+//Game Boy Printer attached to the link port. Print jobs are saved as <prefix>_print_<n>.png
+pub struct Printer {
+	state : PacketState,
+	command : u8,
+	compressed : bool,
+	length : usize,
+	data : Vec<u8>,
+	checksum : u16,
+	received_checksum : u16,
+
+	image : Vec<u8>,
+	status : u8,
+	busy_polls : u32,
+	reply : u8,
+
+	prefix : String,
+	num_prints : u32
+}
+
+impl Printer {
+
+	pub fn new(prefix : &str) -> Printer {
+		Printer {
+			state : PacketState::Magic1,
+			command : 0,
+			compressed : false,
+			length : 0,
+			data : Vec::new(),
+			checksum : 0,
+			received_checksum : 0,
+			image : Vec::with_capacity(BUFFER_SIZE),
+			status : 0,
+			busy_polls : 0,
+			reply : 0,
+			prefix : prefix.to_string(),
+			num_prints : 0
+		}
+	}
+
+	//feed one byte of the packet, returns the byte shifted back to the Game Boy
+	fn process(&mut self, byte : u8) -> u8 {
+		use self::PacketState::*;
+
+		let mut reply = 0x00;
+		self.state = match self.state {
+			Magic1 => if byte == 0x88 { Magic2 } else { Magic1 },
+			Magic2 => if byte == 0x33 { Command } else { Magic1 },
+			Command => {
+				self.command = byte;
+				self.checksum = byte as u16;
+				Compression
+			},
+			Compression => {
+				self.compressed = byte & 1 != 0;
+				self.checksum = self.checksum.wrapping_add(byte as u16);
+				LengthLow
+			},
+			LengthLow => {
+				self.length = byte as usize;
+				self.checksum = self.checksum.wrapping_add(byte as u16);
+				LengthHigh
+			},
+			LengthHigh => {
+				self.length |= (byte as usize) << 8;
+				self.checksum = self.checksum.wrapping_add(byte as u16);
+				self.data.clear();
+				if self.length > 0 { Data } else { ChecksumLow }
+			},
+			Data => {
+				self.data.push(byte);
+				self.checksum = self.checksum.wrapping_add(byte as u16);
+				if self.data.len() == self.length { ChecksumLow } else { Data }
+			},
+			ChecksumLow => {
+				self.received_checksum = byte as u16;
+				ChecksumHigh
+			},
+			ChecksumHigh => {
+				self.received_checksum |= (byte as u16) << 8;
+				Ack
+			},
+			Ack => {
+				reply = DEVICE_ID;
+				self.execute();
+				Status
+			},
+			Status => {
+				reply = self.status;
+				if self.busy_polls > 0 {
+					self.busy_polls -= 1;
+					if self.busy_polls == 0 {
+						self.status &= !STATUS_BUSY;
+					}
+				}
+				Magic1
+			}
+		};
+		reply
+	}
+
+	fn execute(&mut self) {
+		if self.checksum != self.received_checksum {
+			self.status |= STATUS_CHECKSUM_ERROR;
+			return
+		}
+		self.status &= !STATUS_CHECKSUM_ERROR;
+
+		match self.command {
+			CMD_INIT => {
+				self.image.clear();
+				self.status = 0;
+				self.busy_polls = 0;
+			},
+			CMD_DATA => {
+				let data = mem::replace(&mut self.data, Vec::new());
+				if self.compressed {
+					self.decompress(&data);
+				} else {
+					self.image.extend_from_slice(&data);
+				}
+				self.data = data;
+				if !self.image.is_empty() {
+					self.status |= STATUS_UNPROCESSED;
+				}
+				if self.image.len() >= BUFFER_SIZE {
+					self.image.truncate(BUFFER_SIZE);
+					self.status |= STATUS_IMAGE_FULL;
+				}
+			},
+			CMD_PRINT => {
+				if self.data.len() >= 4 {
+					let palette = if self.data[2] == 0 { 0xe4 } else { self.data[2] };
+					self.print(palette);
+				}
+				self.image.clear();
+				self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+				self.status |= STATUS_BUSY;
+				self.busy_polls = BUSY_POLLS;
+			},
+			CMD_STATUS => (),
+			c => println!("printer: unknown command {:02x}", c)
+		}
+	}
+
+	//bit 7 set: repeat the next byte (n & 0x7f) + 2 times, otherwise copy the next n + 1 bytes
+	fn decompress(&mut self, data : &[u8]) {
+		let mut i = 0;
+		while i < data.len() {
+			let n = data[i] as usize;
+			i += 1;
+			if n & 0x80 != 0 {
+				if i < data.len() {
+					for _ in 0..(n & 0x7f) + 2 {
+						self.image.push(data[i]);
+					}
+				}
+				i += 1;
+			} else {
+				let end = ::std::cmp::min(i + n + 1, data.len());
+				self.image.extend_from_slice(&data[i..end]);
+				i = end;
+			}
+		}
+	}
+
+	fn print(&mut self, palette : u8) {
+		let num_bands = self.image.len() / BAND_SIZE;
+		if num_bands == 0 {
+			return
+		}
+		let height = num_bands*16;
+		let mut rgb = vec![0; PRINT_WIDTH*height*3];
+
+		for tile in 0..num_bands*2*TILES_PER_ROW {
+			let tile_data = &self.image[tile*BYTES_PER_TILE..(tile+1)*BYTES_PER_TILE];
+			let (tile_x, tile_y) = ((tile % TILES_PER_ROW)*8, (tile / TILES_PER_ROW)*8);
+			for row in 0..8 {
+				let (low, high) = (tile_data[row*2], tile_data[row*2 + 1]);
+				for col in 0..8 {
+					let index = (((high >> (7-col)) & 1) << 1) | ((low >> (7-col)) & 1);
+					let shade = SHADES[((palette >> (2*index)) & 0x3) as usize];
+					let offset = ((tile_y + row)*PRINT_WIDTH + tile_x + col)*3;
+					rgb[offset] = shade;
+					rgb[offset + 1] = shade;
+					rgb[offset + 2] = shade;
+				}
+			}
+		}
+
+		self.num_prints += 1;
+		let filename = format!("{}_print_{:03}.png", self.prefix, self.num_prints);
+		match image::write_png(&filename, PRINT_WIDTH, height, &rgb) {
+			Ok(_) => println!("printed {}", filename),
+			Err(e) => println!("printer: couldn't write {}: {}", filename, e)
+		}
+	}
+}
+
+impl SerialLink for Printer {
+
+	fn send(&mut self, data : u8) {
+		self.reply = self.process(data);
+	}
+
+	fn receive(&mut self) -> Option<u8> {
+		Some(self.reply)
+	}
+
+	//the printer never drives the clock
+	fn poll_external(&mut self, _ : Option<u8>) -> Option<u8> {
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+	use std::fs;
+	use std::path::Path;
+
+	use super::*;
+	use image;
+
+	//a whole packet with its checksum, plus the two bytes that clock out the acknowledgement and status
+	fn packet(command : u8, compressed : bool, data : &[u8]) -> Vec<u8> {
+		let mut bytes = vec![0x88, 0x33, command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+		bytes.extend_from_slice(data);
+		let checksum = bytes[2..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+		bytes.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8, 0, 0]);
+		bytes
+	}
+
+	//returns the acknowledgement and status bytes
+	fn send(printer : &mut Printer, bytes : &[u8]) -> (u8, u8) {
+		let replies : Vec<u8> = bytes.iter().map(|b| printer.process(*b)).collect();
+		(replies[replies.len() - 2], replies[replies.len() - 1])
+	}
+
+	#[test]
+	fn checksum() {
+		let mut printer = Printer::new("unused");
+		assert_eq!(send(&mut printer, &packet(CMD_STATUS, false, &[])), (DEVICE_ID, 0));
+
+		let mut bad = packet(CMD_DATA, false, &[1, 2, 3]);
+		let checksum_low = bad.len() - 4;
+		bad[checksum_low] ^= 1;
+		assert_eq!(send(&mut printer, &bad), (DEVICE_ID, STATUS_CHECKSUM_ERROR));
+		assert!(printer.image.is_empty());
+
+		//the error is cleared by the next good packet
+		assert_eq!(send(&mut printer, &packet(CMD_DATA, false, &[1, 2, 3])), (DEVICE_ID, STATUS_UNPROCESSED));
+		assert_eq!(printer.image, [1, 2, 3]);
+	}
+
+	#[test]
+	fn decompress() {
+		let mut printer = Printer::new("unused");
+		//a run of 3, 3 literals and a run of 2
+		let data = [0x81, 0xaa, 0x02, 1, 2, 3, 0x80, 0x55];
+		assert_eq!(send(&mut printer, &packet(CMD_DATA, true, &data)), (DEVICE_ID, STATUS_UNPROCESSED));
+		assert_eq!(printer.image, [0xaa, 0xaa, 0xaa, 1, 2, 3, 0x55, 0x55]);
+	}
+
+	#[test]
+	fn print_job() {
+		let prefix = env::temp_dir().join(format!("rustyboy_printer_test_{}", ::std::process::id()));
+		let prefix = prefix.to_string_lossy().into_owned();
+		let mut printer = Printer::new(&prefix);
+		assert_eq!(send(&mut printer, &packet(CMD_INIT, false, &[])), (DEVICE_ID, 0));
+
+		//one band where every tile has its first row in color 3
+		let mut band = vec![0; BAND_SIZE];
+		for tile in band.chunks_mut(BYTES_PER_TILE) {
+			tile[0] = 0xff;
+			tile[1] = 0xff;
+		}
+		send(&mut printer, &packet(CMD_DATA, false, &band));
+		assert_eq!(printer.image.len(), BAND_SIZE);
+
+		//one sheet, no margins, default palette, default exposure
+		let (ack, status) = send(&mut printer, &packet(CMD_PRINT, false, &[1, 0x00, 0xe4, 0x40]));
+		assert_eq!((ack, status), (DEVICE_ID, STATUS_BUSY));
+		assert!(printer.image.is_empty());
+		assert_eq!(printer.num_prints, 1);
+
+		let filename = format!("{}_print_001.png", prefix);
+		let (width, height, rgb) = image::read_png(Path::new(&filename)).unwrap();
+		fs::remove_file(&filename).unwrap();
+		assert_eq!((width, height), (PRINT_WIDTH, 16));
+		assert_eq!(&rgb[0..3], &[0x00, 0x00, 0x00]);
+		assert_eq!(&rgb[PRINT_WIDTH*3..PRINT_WIDTH*3 + 3], &[0xff, 0xff, 0xff]);
+
+		//busy for a few status polls after printing
+		for _ in 1..BUSY_POLLS {
+			assert_eq!(send(&mut printer, &packet(CMD_STATUS, false, &[])).1, STATUS_BUSY);
+		}
+		assert_eq!(send(&mut printer, &packet(CMD_STATUS, false, &[])).1, 0);
+	}
+}
@@ -0,0 +1,74 @@
+use std::io;
+
+use savestate::{SaveState, StateWriter, StateReader};
+
+//things components want to be woken up for
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Event {
+	//the PPU changes mode or line
+	Video,
+	//TIMA overflows
+	TimerOverflow,
+	//a transfer finishes or the link is due to be polled
+	Serial,
+	//the next OAM DMA byte is copied
+	DmaByte
+}
+
+//Keeps the system time in clock cycles and the time of the next event of each kind.
+//Components are only advanced when one of their events is due or when the CPU
+//accesses something that changes between events.
+pub struct Scheduler {
+	pub now : u64,
+	//sorted by time, at most one entry per event kind
+	events : Vec<(u64, Event)>
+}
+
+impl Scheduler {
+
+	pub fn new() -> Scheduler {
+		Scheduler {
+			now : 0,
+			events : Vec::with_capacity(4)
+		}
+	}
+
+	//(re)schedule `event` at the absolute time `at`
+	pub fn schedule(&mut self, at : u64, event : Event) {
+		self.cancel(event);
+		let pos = self.events.iter().position(|e| e.0 > at).unwrap_or(self.events.len());
+		self.events.insert(pos, (at, event));
+	}
+
+	pub fn cancel(&mut self, event : Event) {
+		self.events.retain(|e| e.1 != event);
+	}
+
+	pub fn clear(&mut self) {
+		self.events.clear();
+	}
+
+	//the next event that is due at the current time, with the time it was scheduled for
+	#[inline]
+	pub fn pop_due(&mut self) -> Option<(u64, Event)> {
+		if self.events.first().map_or(false, |e| e.0 <= self.now) {
+			Some(self.events.remove(0))
+		} else {
+			None
+		}
+	}
+}
+
+//pending events are derived from the components and rescheduled after loading
+impl SaveState for Scheduler {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_u64(self.now);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		self.now = try!(r.read_u64());
+		self.events.clear();
+		Ok(())
+	}
+}
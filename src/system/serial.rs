@@ -1,21 +1,239 @@
-use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{self, Write};
 use super::system::MemoryAccess;
 use super::ioregister::IORegister;
+use super::interrupt::{self, InterruptRegisters};
+use savestate::{SaveState, StateWriter, StateReader};
 
 macro_rules! bits {
 	( $($bit:expr)* ) => ( 0x00 $( | (1<<$bit) )* )
 }
 
+const CONTROL_TRANSFER_START : u8 = 1<<7;
+const CONTROL_FAST_CLOCK : u8 = 1<<1;
+const CONTROL_INTERNAL_CLOCK : u8 = 1<<0;
+
+const NORMAL_BIT_CYCLES : u32 = 512; 	//8192 Hz
+const FAST_BIT_CYCLES : u32 = 16;		//262144 Hz (CGB only)
+const POLL_CYCLES : u32 = 512;			//how often the link is asked for externally clocked transfers
+
+//a device on the other end of the link cable
+pub trait SerialLink {
+	//start a transfer clocked by our internal clock
+	fn send(&mut self, data : u8);
+	//the byte shifted in during the last send(), once it is available
+	fn receive(&mut self) -> Option<u8>;
+	//check for a transfer clocked by the partner. `data` is our SB value if we are waiting for
+	//an external clock, None otherwise. Returns the byte shifted in by the partner.
+	fn poll_external(&mut self, data : Option<u8>) -> Option<u8>;
+	//all bytes sent so far, if the backend records them
+	fn captured(&self) -> &[u8] { &[] }
+	//whether the partner can clock a transfer, only then poll_external() is called
+	fn external_clock(&self) -> bool { false }
+}
+
+//no cable connected: the input line is pulled high and nobody clocks us
+pub struct NoLink;
+
+impl SerialLink for NoLink {
+	fn send(&mut self, _ : u8) { }
+	fn receive(&mut self) -> Option<u8> { Some(0xff) }
+	fn poll_external(&mut self, _ : Option<u8>) -> Option<u8> { None }
+}
+
+//records every byte we send and passes the transfer on to another link.
+//test ROMs print their results this way, so it is useful without a partner, too.
+pub struct CaptureLink {
+	inner : Box<SerialLink>,
+	buffer : Vec<u8>,
+	sink : Option<Box<Write>>
+}
+
+impl CaptureLink {
+
+	pub fn new(inner : Box<SerialLink>, sink : Option<Box<Write>>) -> CaptureLink {
+		CaptureLink {
+			inner : inner,
+			buffer : Vec::new(),
+			sink : sink
+		}
+	}
+}
+
+impl SerialLink for CaptureLink {
+
+	fn send(&mut self, data : u8) {
+		self.buffer.push(data);
+		if let Some(ref mut sink) = self.sink {
+			//output is best effort, a closed pipe must not stop the emulation
+			sink.write_all(&[data]).and_then(|_| sink.flush()).unwrap_or(());
+		}
+		self.inner.send(data)
+	}
+
+	fn receive(&mut self) -> Option<u8> {
+		self.inner.receive()
+	}
+
+	fn poll_external(&mut self, data : Option<u8>) -> Option<u8> {
+		self.inner.poll_external(data)
+	}
+
+	fn external_clock(&self) -> bool {
+		self.inner.external_clock()
+	}
+
+	fn captured(&self) -> &[u8] {
+		&self.buffer
+	}
+}
+
 pub struct SerialRegisters {
 	pub data : IORegister,
-	pub control : IORegister	
+	pub control : IORegister,
+	transfer_active : bool,
+	transfer_cycles : u32,
+	poll_cycles : u32,
+	//system time the registers were last brought up to date
+	last_update : u64,
+	link : Box<SerialLink>,
+	interrupt_regs : Rc<RefCell<InterruptRegisters>>
 }
 
-impl Default for SerialRegisters {
-	fn default() -> SerialRegisters {
+impl SerialRegisters {
+
+	pub fn new(iregs : Rc<RefCell<InterruptRegisters>>) -> SerialRegisters {
 		SerialRegisters {
 			control : IORegister::new().write_mask(bits!(7 1 0)),
-			data : IORegister::new()
+			data : IORegister::new(),
+			transfer_active : false,
+			transfer_cycles : 0,
+			poll_cycles : 0,
+			last_update : 0,
+			link : Box::new(NoLink),
+			interrupt_regs : iregs
 		}
 	}
-}
\ No newline at end of file
+
+	pub fn set_link(&mut self, link : Box<SerialLink>) {
+		self.link = link;
+	}
+
+	//disconnects the link, e.g. to plug it into another system
+	pub fn take_link(&mut self) -> Box<SerialLink> {
+		::std::mem::replace(&mut self.link, Box::new(NoLink))
+	}
+
+	pub fn captured(&self) -> &[u8] {
+		self.link.captured()
+	}
+
+	pub fn write_control(&mut self, data : u8) {
+		self.control.write(0, data);
+		if self.transfer_requested() && self.internal_clock() {
+			let bit_cycles = if *self.control & CONTROL_FAST_CLOCK != 0 { FAST_BIT_CYCLES } else { NORMAL_BIT_CYCLES };
+			self.transfer_active = true;
+			self.transfer_cycles = 8*bit_cycles;
+			self.link.send(*self.data);
+		} else {
+			self.transfer_active = false;
+		}
+	}
+
+	//advance to the system time `now`
+	pub fn catch_up(&mut self, now : u64) {
+		if now > self.last_update {
+			//without a transfer nothing is scheduled, so idle gaps can be long
+			let delta = ::std::cmp::min(now - self.last_update, u32::max_value() as u64);
+			self.last_update = now;
+			self.update(delta as u32);
+		}
+	}
+	
+	//system time of the next poll or the end of the running transfer, None when idle
+	pub fn next_event(&self) -> Option<u64> {
+		let transfer = if self.transfer_active && self.transfer_cycles > 0 { Some(self.transfer_cycles) } else { None };
+		//a finished transfer waiting for the partner is retried when polling
+		let cycles = match (self.polling(), transfer) {
+			(true, Some(cycles)) => Some(::std::cmp::min(POLL_CYCLES.saturating_sub(self.poll_cycles), cycles)),
+			(true, None) => Some(POLL_CYCLES.saturating_sub(self.poll_cycles)),
+			(false, transfer) => transfer
+		};
+		cycles.map(|c| self.last_update + ::std::cmp::max(c, 1) as u64)
+	}
+	
+	fn update(&mut self, delta : u32) {
+
+		if self.polling() {
+			self.poll_cycles = self.poll_cycles.saturating_add(delta);
+			if self.poll_cycles >= POLL_CYCLES {
+				self.poll_cycles = 0;
+				let waiting = if !self.internal_clock() { Some(*self.data) } else { None };
+				if let Some(received) = self.link.poll_external(waiting) {
+					if waiting.is_some() {
+						*self.data = received;
+						self.finish_transfer();
+					}
+				}
+			}
+		}
+
+		if self.transfer_active {
+			self.transfer_cycles = self.transfer_cycles.saturating_sub(delta);
+			if self.transfer_cycles == 0 {
+				//the partner may still be busy, keep the transfer pending until its byte arrived
+				if let Some(received) = self.link.receive() {
+					*self.data = received;
+					self.transfer_active = false;
+					self.finish_transfer();
+				}
+			}
+		}
+	}
+
+	fn finish_transfer(&mut self) {
+		*self.control &= !CONTROL_TRANSFER_START;
+		let mut iregs = self.interrupt_regs.borrow_mut();
+		*iregs.iflags |= interrupt::INTERRUPT_SERIAL;
+	}
+
+	//only a partner that can drive the clock has to be asked, and only while SC bit 7 is set
+	#[inline]
+	fn polling(&self) -> bool {
+		self.transfer_requested() && self.link.external_clock()
+	}
+
+	#[inline]
+	fn transfer_requested(&self) -> bool {
+		*self.control & CONTROL_TRANSFER_START != 0
+	}
+
+	#[inline]
+	fn internal_clock(&self) -> bool {
+		*self.control & CONTROL_INTERNAL_CLOCK != 0
+	}
+}
+
+//the link backend is not part of the state, it stays connected
+impl SaveState for SerialRegisters {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		self.data.save_state(w);
+		self.control.save_state(w);
+		w.write_bool(self.transfer_active);
+		w.write_u32(self.transfer_cycles);
+		w.write_u32(self.poll_cycles);
+		w.write_u64(self.last_update);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		try!(self.data.load_state(r));
+		try!(self.control.load_state(r));
+		self.transfer_active = try!(r.read_bool());
+		self.transfer_cycles = try!(r.read_u32());
+		self.poll_cycles = try!(r.read_u32());
+		self.last_update = if r.version >= 4 { try!(r.read_u64()) } else { 0 };
+		Ok(())
+	}
+}
@@ -1,4 +1,6 @@
+use std::io;
 use super::system::MemoryAccess;
+use savestate::{SaveState, StateWriter, StateReader};
 use super::ioregister::IORegister;
 
 macro_rules! bits {
@@ -88,4 +90,31 @@ impl MemoryAccess for WaveRAM {
 	fn write(&mut self, addr: u16, data: u8) {
 		self.0[addr as usize] = data;
 	}
-}
\ No newline at end of file
+}
+
+impl SaveState for SoundData {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		let regs = &self.regs;
+		for reg in &[&regs.ctrl_vol, &regs.ctrl_ch_mux, &regs.ctrl_on_off,
+				&regs.ch1_sweep, &regs.ch1_length_duty, &regs.ch1_vol_env, &regs.ch1_freq_low, &regs.ch1_freq_high,
+				&regs.ch2_length_duty, &regs.ch2_vol_env, &regs.ch2_freq_low, &regs.ch2_freq_high,
+				&regs.ch3_snd_on_off, &regs.ch3_snd_length, &regs.ch3_out_level, &regs.ch3_freq_low, &regs.ch3_freq_high,
+				&regs.ch4_snd_length, &regs.ch4_vol_env, &regs.ch4_poly_cnt, &regs.ch4_cnt_init] {
+			reg.save_state(w);
+		}
+		w.write_bytes(&self.wave_ram.0);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		let regs = &mut self.regs;
+		for reg in &mut [&mut regs.ctrl_vol, &mut regs.ctrl_ch_mux, &mut regs.ctrl_on_off,
+				&mut regs.ch1_sweep, &mut regs.ch1_length_duty, &mut regs.ch1_vol_env, &mut regs.ch1_freq_low, &mut regs.ch1_freq_high,
+				&mut regs.ch2_length_duty, &mut regs.ch2_vol_env, &mut regs.ch2_freq_low, &mut regs.ch2_freq_high,
+				&mut regs.ch3_snd_on_off, &mut regs.ch3_snd_length, &mut regs.ch3_out_level, &mut regs.ch3_freq_low, &mut regs.ch3_freq_high,
+				&mut regs.ch4_snd_length, &mut regs.ch4_vol_env, &mut regs.ch4_poly_cnt, &mut regs.ch4_cnt_init] {
+			try!(reg.load_state(r));
+		}
+		r.read_bytes(&mut self.wave_ram.0)
+	}
+}
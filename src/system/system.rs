@@ -1,4 +1,4 @@
-use std::io::Write;
+use std::io::{self, Write};
 
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -13,9 +13,12 @@ use super::video::{VideoData, VRAMBank, OAM};
 use super::sound::SoundData;
 use super::timer::TimerRegisters;
 use super::interrupt::InterruptRegisters;
-use super::serial::SerialRegisters;
+use super::serial::{SerialRegisters, SerialLink};
 use super::wram::*;
 use super::joypad::Joypad;
+use super::scheduler::{Scheduler, Event};
+use super::watchpoint::{Watchpoint, WatchHit};
+use savestate::{SaveState, StateWriter, StateReader};
 
 
 macro_rules! bits {
@@ -64,6 +67,16 @@ pub struct GBSystem {
 	
 	zero_page : ZeroPageRAM,
 	dummy : IODummy,
+	
+	scheduler : Scheduler,
+	
+	//CGB speed switch (KEY1)
+	pub double_speed : bool,
+	speed_switch_armed : bool,
+	
+	//set by the debugger, which collects the hits after each instruction
+	pub watchpoints : Vec<Watchpoint>,
+	pub watch_hits : Vec<WatchHit>,
 }
 
 impl GBSystem {
@@ -73,7 +86,7 @@ impl GBSystem {
 		//generate shared iregs instance first
 		let iregs = Rc::new(RefCell::new(InterruptRegisters{ ..Default::default() }));
 		
-		GBSystem {
+		let mut sys = GBSystem {
 			mbc : MBC::new(rom),
 			wram0 : WRAMBank(Box::new([0; WRAM_BANK_SIZE])),
 			wram1 : WRAMBank(Box::new([0; WRAM_BANK_SIZE])),
@@ -81,34 +94,133 @@ impl GBSystem {
 			interrupt_regs : iregs.clone(),
 			timer_regs : TimerRegisters::new(iregs.clone()),
 			sound : SoundData{ ..Default::default() },
-			serial_regs : SerialRegisters{ ..Default::default() },
+			serial_regs : SerialRegisters::new(iregs.clone()),
 			zero_page : ZeroPageRAM(Box::new([0; 128])),
 			joypad: Joypad::new(iregs.clone()),
-			dummy: IODummy
-		}
+			dummy: IODummy,
+			scheduler : Scheduler::new(),
+			double_speed : false,
+			speed_switch_armed : false,
+			watchpoints : Vec::new(),
+			watch_hits : Vec::new()
+		};
+		sys.reschedule_all();
+		sys
+	}
+	
+	pub fn set_serial_link(&mut self, link : Box<SerialLink>) {
+		self.serial_regs.set_link(link);
+		self.schedule_serial();
 	}
 	
+	pub fn take_serial_link(&mut self) -> Box<SerialLink> {
+		let link = self.serial_regs.take_link();
+		self.schedule_serial();
+		link
+	}
+	
+	pub fn serial_output(&self) -> &[u8] {
+		self.serial_regs.captured()
+	}
+	
+	//advance the system time. Components only run when one of their events is due.
 	pub fn update(&mut self, delta: u32) {
-		
-		self.video.update(delta);
-		self.timer_regs.update(delta);
-		
-		for _ in 0..(delta/4) {
-			if self.video.oam.dma_transfer {
-				let addr = self.video.oam.dma_addr;
-				let data = self.read8(addr);
-				let index = addr & 0xff;
-				self.video.oam.write(index, data);
-				if index + 1 == 0xa0 {
-					self.video.oam.dma_transfer = false;
-				}
-				self.video.oam.dma_addr = addr+1;
+		self.scheduler.now += delta as u64;
+		while let Some((time, event)) = self.scheduler.pop_due() {
+			match event {
+				Event::Video => {
+					self.video.catch_up(time, self.double_speed);
+					self.scheduler.schedule(self.video.next_event(self.double_speed), Event::Video);
+				},
+				Event::TimerOverflow => {
+					self.timer_regs.catch_up(time);
+					self.schedule_timer();
+				},
+				Event::Serial => {
+					self.serial_regs.catch_up(time);
+					self.schedule_serial();
+				},
+				Event::DmaByte => self.dma_step(time)
 			}
 		}
 	}
+	
+	//copy one byte of a running OAM DMA transfer, one per M-cycle
+	fn dma_step(&mut self, time : u64) {
+		if !self.video.oam.dma_transfer {
+			return
+		}
+		let addr = self.video.oam.dma_addr;
+		let data = self.peek8(addr);
+		let index = addr & 0xff;
+		self.video.oam.write(index, data);
+		if index + 1 == 0xa0 {
+			self.video.oam.dma_transfer = false;
+		} else {
+			self.scheduler.schedule(time + 4, Event::DmaByte);
+		}
+		self.video.oam.dma_addr = addr+1;
+	}
+	
+	//the timer registers change between events, so they are caught up on access
+	fn timer(&mut self) -> &mut TimerRegisters {
+		self.timer_regs.catch_up(self.scheduler.now);
+		&mut self.timer_regs
+	}
+	
+	fn schedule_timer(&mut self) {
+		match self.timer_regs.next_overflow() {
+			Some(time) => self.scheduler.schedule(time, Event::TimerOverflow),
+			None => self.scheduler.cancel(Event::TimerOverflow)
+		}
+	}
+	
+	fn schedule_serial(&mut self) {
+		match self.serial_regs.next_event() {
+			Some(time) => self.scheduler.schedule(time, Event::Serial),
+			None => self.scheduler.cancel(Event::Serial)
+		}
+	}
+	
+	//pending events aren't saved, they follow from the state of the components
+	fn reschedule_all(&mut self) {
+		self.scheduler.clear();
+		self.scheduler.schedule(self.video.next_event(self.double_speed), Event::Video);
+		self.schedule_serial();
+		self.schedule_timer();
+		if self.video.oam.dma_transfer {
+			let now = self.scheduler.now;
+			self.scheduler.schedule(now + 4, Event::DmaByte);
+		}
+	}
 
 
     pub fn read8(&mut self, addr: u16) -> u8 {
+    	let data = self.peek8(addr);
+    	if !self.watchpoints.is_empty() {
+    		self.check_watchpoints(addr, false, data, data);
+    	}
+    	data
+    }
+    
+    pub fn write8(&mut self, addr: u16, data: u8) {
+    	if !self.watchpoints.is_empty() {
+    		let old = self.peek8(addr);
+    		self.check_watchpoints(addr, true, old, data);
+    	}
+    	self.store8(addr, data)
+    }
+    
+    fn check_watchpoints(&mut self, addr: u16, write: bool, old: u8, new: u8) {
+    	for (i, w) in self.watchpoints.iter().enumerate() {
+    		if w.matches(addr, write, new) {
+    			self.watch_hits.push(WatchHit { index : i, addr : addr, write : write, old : old, new : new });
+    		}
+    	}
+    }
+    
+    //a read that doesn't trigger watchpoints, for instruction fetches, DMA and the debugger
+    pub fn peek8(&mut self, addr: u16) -> u8 {
     	let addr_l = addr as u8;
     	let addr_h = (addr >> 8) as u8;
 		match addr_h {
@@ -128,8 +240,8 @@ impl GBSystem {
 				0x00 => self.joypad.get_register(), 					//JOYPAD
 				0x01 => self.serial_regs.data.read(addr),		//SB
 				0x02 => self.serial_regs.control.read(addr),		//SC
-				0x04 => self.timer_regs.read_divider(),
-				0x05 => self.timer_regs.read_counter(),
+				0x04 => self.timer().read_divider(),
+				0x05 => self.timer().read_counter(),
 				0x06 => self.timer_regs.modulo.read(addr),
 				0x07 => self.timer_regs.control.read(addr),
 				
@@ -171,6 +283,7 @@ impl GBSystem {
 				0x49 => self.video.get_obp1_palette(),								// OBP1
 				0x4a => self.video.regs.wy.read(addr),								// WY
 				0x4b => self.video.regs.wx.read(addr),								// Wx
+				0x4d if self.mbc.rom.cgb_flag => self.read_key1(),				// KEY1
 				
 				0x80 ... 0xfe => self.zero_page.read(addr - 0xff80),			// Zero Page RAM
 				0xff => self.interrupt_regs.borrow_mut().ienable.read(addr),			// IE
@@ -180,7 +293,7 @@ impl GBSystem {
 		}
     }
 
-    pub fn write8(&mut self, addr: u16, data: u8) {
+    fn store8(&mut self, addr: u16, data: u8) {
     	let addr_l = addr as u8;
     	let addr_h = (addr >> 8) as u8;
 		match addr_h {
@@ -199,11 +312,15 @@ impl GBSystem {
 			0xff => match addr_l {
 				0x00 => self.joypad.set_register(data),					//JOYPAD
 				0x01 => self.serial_regs.data.write(addr, data),		//SB
-				0x02 => self.serial_regs.control.write(addr, data),		//SC
-				0x04 => self.timer_regs.clear_divider(),
-				0x05 => self.timer_regs.write_counter(data),
-				0x06 => self.timer_regs.modulo.write(addr, data),
-				0x07 => self.timer_regs.control.write(addr, data),
+				0x02 => {																//SC
+					self.serial_regs.catch_up(self.scheduler.now);
+					self.serial_regs.write_control(data);
+					self.schedule_serial();
+				},
+				0x04 => { self.timer().clear_divider(); self.schedule_timer() },
+				0x05 => { self.timer().write_counter(data); self.schedule_timer() },
+				0x06 => { self.timer().modulo.write(addr, data); self.schedule_timer() },
+				0x07 => { self.timer().control.write(addr, data); self.schedule_timer() },
 				
 	    		0x0f => self.interrupt_regs.borrow_mut().iflags.write(addr, data),
 				0x10 => self.sound.regs.ch1_sweep.write(addr, data),
@@ -237,12 +354,19 @@ impl GBSystem {
 				0x43 => self.video.regs.scx.write(addr, data),								// SCX
 				0x44 => self.video.regs.ly.write(addr, data),								// LY
 				0x45 => self.video.regs.lyc.write(addr, data),								// LYC
-				0x46 => self.video.oam.trigger_dma(data),									// OAM DMA transfer here
+				0x46 => {																	// OAM DMA transfer here
+					self.video.oam.trigger_dma(data);
+					if self.video.oam.dma_transfer {
+						let now = self.scheduler.now;
+						self.scheduler.schedule(now + 4, Event::DmaByte);
+					}
+				},
 				0x47 => self.video.set_bg_palette(data),									// BGP
 				0x48 => self.video.set_obp0_palette(data),									// OBP0
 				0x49 => self.video.set_obp1_palette(data),									// OBP1
 				0x4a => self.video.regs.wy.write(addr, data),								// WY
 				0x4b => self.video.regs.wx.write(addr, data),								// Wx
+				0x4d if self.mbc.rom.cgb_flag => self.speed_switch_armed = data & 0x01 != 0,	// KEY1
 				
 				0x80 ... 0xfe => self.zero_page.write(addr - 0xff80, data),			// Zero Page RAM
 				0xff => self.interrupt_regs.borrow_mut().ienable.write(addr, data),			// IE
@@ -251,9 +375,33 @@ impl GBSystem {
 			_ => unreachable!()
 		}
     }
+    
+    fn read_key1(&self) -> u8 {
+    	((self.double_speed as u8) << 7) | 0x7e | (self.speed_switch_armed as u8)
+    }
+    
+    //executed on STOP
+    pub fn switch_speed(&mut self) -> Option<bool> {
+    	if !self.speed_switch_armed {
+    		return None
+    	}
+    	self.speed_switch_armed = false;
+    	self.video.catch_up(self.scheduler.now, self.double_speed);
+    	self.double_speed = !self.double_speed;
+    	self.scheduler.schedule(self.video.next_event(self.double_speed), Event::Video);
+    	Some(self.double_speed)
+    }
+    
+    //like a write to DIV, but not by the CPU, so watchpoints and the history don't see it
+    pub fn reset_divider(&mut self) {
+    	self.timer().clear_divider();
+    	self.schedule_timer()
+    }
        
+    //low byte first, like the CPU reads it
     pub fn read16(&mut self, addr: u16) -> u16 {
-    	((self.read8(addr.wrapping_add(1)) as u16) << 8) | (self.read8(addr) as u16)
+    	let low = self.read8(addr) as u16;
+    	low | ((self.read8(addr.wrapping_add(1)) as u16) << 8)
     }
     
     pub fn write16(&mut self, addr: u16, data: u16) {
@@ -261,3 +409,175 @@ impl GBSystem {
     	self.write8(addr.wrapping_add(1), (data >> 8) as u8)
     }
 }
+
+impl Memory for GBSystem {
+
+	fn read8(&mut self, addr: u16) -> u8 {
+		GBSystem::read8(self, addr)
+	}
+
+	fn write8(&mut self, addr: u16, data: u8) {
+		GBSystem::write8(self, addr, data)
+	}
+
+	fn peek8(&mut self, addr: u16) -> u8 {
+		GBSystem::peek8(self, addr)
+	}
+
+	fn update(&mut self, delta: u32) {
+		GBSystem::update(self, delta)
+	}
+
+	fn switch_speed(&mut self) -> Option<bool> {
+		GBSystem::switch_speed(self)
+	}
+
+	fn reset_divider(&mut self) {
+		GBSystem::reset_divider(self)
+	}
+
+	fn code_bank(&self, addr: u16) -> Option<u16> {
+		match addr {
+			//an instruction at the end of bank 0 reaches into the switchable bank
+			0x0000 ... 0x3ffd => Some(0),
+			0x4000 ... 0x7fff => Some(self.mbc.rom_bank() as u16),
+			//cartridge RAM may be disabled or banked
+			0x8000 ... 0x9fff | 0xc000 ... 0xfdff | 0xff80 ... 0xfffe => Some(0),
+			_ => None
+		}
+	}
+
+	fn yield_requested(&self) -> bool {
+		self.video.frame_ready || !self.watch_hits.is_empty()
+	}
+}
+
+impl SaveState for GBSystem {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		self.mbc.save_state(w);
+		self.video.save_state(w);
+		self.sound.save_state(w);
+		self.interrupt_regs.borrow().save_state(w);
+		self.joypad.save_state(w);
+		self.wram0.save_state(w);
+		self.wram1.save_state(w);
+		self.timer_regs.save_state(w);
+		self.serial_regs.save_state(w);
+		self.zero_page.save_state(w);
+		w.write_bool(self.double_speed);
+		w.write_bool(self.speed_switch_armed);
+		self.scheduler.save_state(w);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		try!(self.mbc.load_state(r));
+		try!(self.video.load_state(r));
+		try!(self.sound.load_state(r));
+		try!(self.interrupt_regs.borrow_mut().load_state(r));
+		try!(self.joypad.load_state(r));
+		try!(self.wram0.load_state(r));
+		try!(self.wram1.load_state(r));
+		try!(self.timer_regs.load_state(r));
+		try!(self.serial_regs.load_state(r));
+		try!(self.zero_page.load_state(r));
+		if r.version >= 3 {
+			self.double_speed = try!(r.read_bool());
+			self.speed_switch_armed = try!(r.read_bool());
+		} else {
+			self.double_speed = false;
+			self.speed_switch_armed = false;
+		}
+		if r.version >= 4 {
+			try!(self.scheduler.load_state(r));
+		} else {
+			//the components' clocks start from zero, too
+			self.scheduler.now = 0;
+		}
+		self.reschedule_all();
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use savestate::{self, SaveState, StateWriter};
+	use core::cpu::CPU;
+	use input::BUTTON_START;
+	use rom::Rom;
+	use system;
+
+	fn test_machine() -> CPU {
+		let rom = Rom::create_from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms/serial.gb")).unwrap();
+		system::init(rom)
+	}
+
+	fn component<T : SaveState>(c : &T) -> Vec<u8> {
+		let mut w = StateWriter::new();
+		c.save_state(&mut w);
+		w.into_inner()
+	}
+
+	//drop the u64 clock versions before 4 don't have
+	fn without_clock(mut data : Vec<u8>) -> Vec<u8> {
+		let len = data.len() - 8;
+		data.truncate(len);
+		data
+	}
+
+	//the state as version 1 wrote it: no lock up, EI delay or HALT bug in the CPU, no clocks in
+	//the components, no held keys, speed switch or scheduler
+	fn save_v1(cpu : &CPU) -> Vec<u8> {
+		let sys = &cpu.sys;
+		let mut data = b"RBSS".to_vec();
+		data.extend_from_slice(&[1, 0]);
+		let checksum = sys.mbc.rom.global_checksum();
+		data.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8, sys.mbc.rom.title.len() as u8]);
+		data.extend_from_slice(sys.mbc.rom.title.as_bytes());
+		data.extend(component(&cpu.regs));
+		data.extend_from_slice(&[cpu.halt_mode as u8, cpu.stop_mode as u8]);
+		data.extend(component(&sys.mbc));
+		data.extend(without_clock(component(&sys.video)));
+		data.extend(component(&sys.sound));
+		data.extend(component(&*sys.interrupt_regs.borrow()));
+		data.push(component(&sys.joypad)[0]);
+		data.extend(component(&sys.wram0));
+		data.extend(component(&sys.wram1));
+		//an unused u32 cycle counter instead of the clock
+		data.extend(without_clock(component(&sys.timer_regs)));
+		data.extend_from_slice(&[0; 4]);
+		data.extend(without_clock(component(&sys.serial_regs)));
+		data.extend(component(&sys.zero_page));
+		data
+	}
+
+	#[test]
+	fn load_version_1() {
+		let mut cpu = test_machine();
+		for _ in 0..1000 {
+			cpu.run_instruction().unwrap();
+		}
+		let state = save_v1(&cpu);
+
+		let mut other = test_machine();
+		other.sys.joypad.set_buttons(BUTTON_START);
+		other.halt_bug = true;
+		savestate::load(&mut other, &state).unwrap();
+		assert_eq!(other.regs.pc, cpu.regs.pc);
+		assert_eq!(other.regs.sp, cpu.regs.sp);
+		assert_eq!(other.regs.af, cpu.regs.af);
+		assert!(!other.halt_bug && !other.locked_up && other.ei_delay == 0);
+		for addr in (0xc000..0xe000).chain(0xff80..0xffff) {
+			assert_eq!(other.sys.peek8(addr), cpu.sys.peek8(addr));
+		}
+		//held keys weren't saved, they stay as they are
+		assert_eq!(other.sys.joypad.buttons(), BUTTON_START);
+		assert_eq!(other.sys.scheduler.now, 0);
+		assert!(!other.sys.double_speed);
+
+		//and it keeps running from there
+		for _ in 0..1000 {
+			other.run_instruction().unwrap();
+		}
+	}
+}
@@ -0,0 +1,125 @@
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{self, Read, Write, ErrorKind};
+use super::serial::SerialLink;
+
+//every message is two bytes: type and data
+const MSG_TRANSFER : u8 = 0x01;	//partner clocked a byte out
+const MSG_REPLY : u8 = 0x02;	//byte shifted back in response to a transfer
+
+//link cable between two emulator instances
+pub struct TcpLink {
+	stream : Option<TcpStream>,
+	rx_buffer : Vec<u8>,
+	incoming : Option<u8>,
+	reply : Option<u8>
+}
+
+impl TcpLink {
+
+	pub fn listen(port : u16) -> io::Result<TcpLink> {
+		let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+		println!("waiting for link partner on port {}...", port);
+		let (stream, addr) = try!(listener.accept());
+		println!("link partner connected from {}", addr);
+		TcpLink::from_stream(stream)
+	}
+
+	pub fn connect<A: ToSocketAddrs>(addr : A) -> io::Result<TcpLink> {
+		let stream = try!(TcpStream::connect(addr));
+		println!("connected to link partner {}", try!(stream.peer_addr()));
+		TcpLink::from_stream(stream)
+	}
+
+	fn from_stream(stream : TcpStream) -> io::Result<TcpLink> {
+		try!(stream.set_nodelay(true));
+		try!(stream.set_nonblocking(true));
+		Ok(TcpLink {
+			stream : Some(stream),
+			rx_buffer : Vec::new(),
+			incoming : None,
+			reply : None
+		})
+	}
+
+	fn disconnect(&mut self, reason : &str) {
+		if self.stream.is_some() {
+			println!("link partner disconnected: {}", reason);
+			self.stream = None;
+		}
+	}
+
+	fn send_message(&mut self, msg : u8, data : u8) {
+		let result = match self.stream {
+			Some(ref mut stream) => stream.write_all(&[msg, data]),
+			None => return
+		};
+		if let Err(e) = result {
+			self.disconnect(&e.to_string());
+		}
+	}
+
+	fn poll(&mut self) {
+		let mut buf = [0; 64];
+		loop {
+			let result = match self.stream {
+				Some(ref mut stream) => stream.read(&mut buf),
+				None => return
+			};
+			match result {
+				Ok(0) => {
+					self.disconnect("connection closed");
+					break
+				},
+				Ok(n) => self.rx_buffer.extend_from_slice(&buf[..n]),
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) => {
+					self.disconnect(&e.to_string());
+					break
+				}
+			}
+		}
+
+		while self.rx_buffer.len() >= 2 {
+			let (msg, data) = (self.rx_buffer[0], self.rx_buffer[1]);
+			self.rx_buffer.drain(..2);
+			match msg {
+				MSG_TRANSFER => self.incoming = Some(data),
+				MSG_REPLY => self.reply = Some(data),
+				_ => println!("ignoring invalid link message {:02x}", msg)
+			}
+		}
+	}
+}
+
+impl SerialLink for TcpLink {
+
+	fn send(&mut self, data : u8) {
+		self.reply = None;
+		self.send_message(MSG_TRANSFER, data);
+	}
+
+	fn receive(&mut self) -> Option<u8> {
+		self.poll();
+		if self.stream.is_none() {
+			//lost the partner, the line floats high
+			return Some(self.reply.take().unwrap_or(0xff))
+		}
+		self.reply.take()
+	}
+
+	fn poll_external(&mut self, data : Option<u8>) -> Option<u8> {
+		self.poll();
+		match self.incoming.take() {
+			Some(received) => {
+				//always answer, otherwise the partner would wait forever
+				self.send_message(MSG_REPLY, data.unwrap_or(0xff));
+				Some(received)
+			},
+			None => None
+		}
+	}
+
+	fn external_clock(&self) -> bool {
+		true
+	}
+}
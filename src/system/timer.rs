@@ -1,19 +1,19 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
 
 use super::system::MemoryAccess;
 use super::ioregister::IORegister;
 use super::interrupt::{self, InterruptRegisters};
+use savestate::{SaveState, StateWriter, StateReader};
 
 macro_rules! bits {
 	( $($bit:expr)* ) => ( 0x00 $( | (1<<$bit) )* )
 }
 
-const CLK_DIVIDER :u32 = 256;
 const TIMER_ENABLE_MASK : u8 = 0x04;
 const CLK_INPUT_MASK : u8 = 0x3;
 
-const TIMER_LUT : [u32;4] = [1024, 16, 64, 256];
 const MUX_LUT : [u16; 4] = [9, 3, 5 ,7]; 
 
 pub struct DividerRegister(pub u16);
@@ -23,51 +23,52 @@ pub struct TimerRegisters {
 	counter : u16,
 	pub modulo : IORegister,
 	pub control : IORegister,
-	counter_cycles : u32,
+	//system time the registers were last brought up to date
+	last_update : u64,
 	interrupt_regs : Rc<RefCell<InterruptRegisters>>
 }
 
 impl TimerRegisters {
-	pub fn update(&mut self, delta : u32) {
-		
-		self.update_cycle_by_cycle(delta)
-		/*		
-		self.divider.0 = self.divider.0.wrapping_add(delta as u16);
-		self.counter_cycles += delta;
+	
+	//advance to the system time `now`. DIV and TIMA change all the time, so this
+	//has to be done before they are accessed.
+	pub fn catch_up(&mut self, now : u64) {
+		if now <= self.last_update {
+			return
+		}
+		let delta = now - self.last_update;
+		self.last_update = now;
 		
-		let timer_enabled = (*self.control & TIMER_ENABLE_MASK) != 0;
-		let max_cycles = TIMER_LUT[(*self.control & CLK_INPUT_MASK) as usize];
-		if timer_enabled && (self.counter_cycles >= max_cycles) {
-			self.counter_cycles %= max_cycles;
-			if *self.counter == 255 {
-				//generate timer overflow interrupt request
-				let mut iregs = self.interrupt_regs.borrow_mut();
-				*iregs.iflags = *iregs.iflags | interrupt::INTERRUPT_TIMER; 
-				
-				*self.counter = *self.modulo;
-			} else {
-				*self.counter += 1;
-			}
-		}*/
-
+		if self.enabled() {
+			//TIMA counts the falling edges of the selected divider bit
+			let shift = self.edge_shift();
+			let divider = self.divider as u64;
+			let edges = ((divider + delta) >> shift) - (divider >> shift);
+			self.increase_counter(edges);
+		}
+		self.divider = self.divider.wrapping_add(delta as u16);
 	}
 	
-	fn update_cycle_by_cycle(&mut self, delta : u32) {
-		
-		let timer_enabled = (*self.control & TIMER_ENABLE_MASK) != 0;
-
-		for _ in 0..delta {
-			let next_divider = self.divider.wrapping_add(1);
-			
-			if timer_enabled && self.falling_edge(next_divider) {
-				
-				self.increase_counter();
-			};
-			self.divider = next_divider;
+	//system time of the next overflow, if the timer is running
+	pub fn next_overflow(&self) -> Option<u64> {
+		if !self.enabled() {
+			return None
 		}
-
+		let period = 1u64 << self.edge_shift();
+		let first_edge = period - (self.divider as u64 & (period - 1));
+		Some(self.last_update + first_edge + (255 - self.counter as u64) * period)
 	}
 	
+	#[inline]
+	fn enabled(&self) -> bool {
+		(*self.control & TIMER_ENABLE_MASK) != 0
+	}
+	
+	//the selected bit falls every 2^edge_shift cycles
+	#[inline]
+	fn edge_shift(&self) -> u16 {
+		MUX_LUT[(*self.control & CLK_INPUT_MASK) as usize] + 1
+	}
 	
 	fn falling_edge(&self, new : u16) -> bool {
 		
@@ -77,10 +78,15 @@ impl TimerRegisters {
 		h2l_bits & (1<<MUX_LUT[mux_a]) != 0
 	}
 	
-	fn increase_counter(&mut self) {
+	fn increase_counter(&mut self, mut ticks : u64) {
 
-		self.counter += 1;
-		if self.counter == 256 {
+		while ticks > 0 {
+			let to_overflow = 256 - self.counter as u64;
+			if ticks < to_overflow {
+				self.counter += ticks as u16;
+				return
+			}
+			ticks -= to_overflow;
 			//generate timer overflow interrupt request
 			let mut iregs = self.interrupt_regs.borrow_mut();
 			*iregs.iflags = *iregs.iflags | interrupt::INTERRUPT_TIMER;
@@ -97,7 +103,7 @@ impl TimerRegisters {
 			counter : 0,
 			modulo : IORegister::new(),
 			control : IORegister::new().write_mask(bits!(2 1 0)),
-			counter_cycles : 0,
+			last_update : 0,
 			interrupt_regs : iregs,
 		}
 	}
@@ -113,10 +119,9 @@ impl TimerRegisters {
 	pub fn clear_divider(&mut self) {
 		//When writing to DIV, if the current output is '1' and timer is enabled, as the new value after reseting DIV will be '0', 
 		//the falling edge detector will detect a falling edge and TIMA will increase.
-		let timer_enabled = (*self.control & TIMER_ENABLE_MASK) != 0;
 		let next_divider = 0 as u16;
-		if timer_enabled && self.falling_edge(0) {
-			self.increase_counter();
+		if self.enabled() && self.falling_edge(0) {
+			self.increase_counter(1);
 		}
 		self.divider = next_divider;
 	}
@@ -124,4 +129,30 @@ impl TimerRegisters {
 	pub fn read_divider(&self) -> u8 {
 		(self.divider >> 8) as u8
 	}
-}
\ No newline at end of file
+}
+
+impl SaveState for TimerRegisters {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_u16(self.divider);
+		w.write_u16(self.counter);
+		self.modulo.save_state(w);
+		self.control.save_state(w);
+		w.write_u64(self.last_update);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		self.divider = try!(r.read_u16());
+		self.counter = try!(r.read_u16());
+		try!(self.modulo.load_state(r));
+		try!(self.control.load_state(r));
+		self.last_update = if r.version >= 4 {
+			try!(r.read_u64())
+		} else {
+			//an unused cycle counter
+			try!(r.read_u32());
+			0
+		};
+		Ok(())
+	}
+}
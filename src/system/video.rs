@@ -1,5 +1,6 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
 use std::cmp::Ordering;
 use std::boxed::Box;
 use self::VideoMode::*;
@@ -7,6 +8,7 @@ use super::interrupt::{self, InterruptRegisters};
 use std::cmp::max;
 use super::system::MemoryAccess;
 use super::ioregister::IORegister;
+use savestate::{SaveState, StateWriter, StateReader};
 
 const VRAM_BANK_SIZE : usize = 8*1024; //8K per VRAM bank
 const OAM_NUM_SPRITES : usize = 40;
@@ -121,6 +123,8 @@ pub struct VideoData {
 	interrupt_regs : Rc<RefCell<InterruptRegisters>>,
 	mode : VideoMode,
 	mode_cycles : u32,
+	//system time the PPU was last brought up to date
+	last_update : u64,
 	bg_palette : Palette,
 	obp_palette : [Palette; 2], 
 	
@@ -141,6 +145,7 @@ impl VideoData {
 			lcd_ctrl : LCDControlRegister::new(),
 			oam : OAM::new(),
 			mode_cycles : 0,
+			last_update : 0,
 			mode : VBLANK,
 			interrupt_regs : iregs,
 			bg_palette : [White, LightGray, DarkGray, Black],
@@ -150,7 +155,42 @@ impl VideoData {
 		}
 	}
 	
-	pub fn update(&mut self, delta : u32) {
+	//advance to the system time `now`. The LCD doesn't run any faster in double speed mode.
+	pub fn catch_up(&mut self, now : u64, double_speed : bool) {
+		let shift = double_speed as u32;
+		let mut dots = now.saturating_sub(self.last_update) >> shift;
+		self.last_update += dots << shift;
+		loop {
+			let to_next = self.cycles_to_next_mode() as u64;
+			if to_next > dots {
+				break
+			}
+			self.step(to_next as u32);
+			dots -= to_next;
+		}
+		if dots > 0 {
+			self.step(dots as u32);
+		}
+	}
+	
+	//system time of the next mode or line change
+	pub fn next_event(&self, double_speed : bool) -> u64 {
+		self.last_update + ((self.cycles_to_next_mode() as u64) << (double_speed as u32))
+	}
+	
+	fn cycles_to_next_mode(&self) -> u32 {
+		let period = match self.mode {
+			//we power on in VBLANK on line 0, which ends it right away
+			VBLANK if *self.regs.ly == 0 => 0,
+			HBLANK | VBLANK => HBLANK_PERIOD,
+			ACCESS_OAM => SCANLINE_OAM_PERIOD,
+			ACCESS_VRAM => SCANLINE_VRAM_PERIOD
+		};
+		period.saturating_sub(self.mode_cycles)
+	}
+	
+	//handles at most one mode change
+	fn step(&mut self, delta : u32) {
 		self.mode_cycles += delta;
 		//let regs = &mut self.regs;
 
@@ -519,3 +559,108 @@ impl LCDControlRegister {
 	}
 }
 
+impl SaveState for VRAMBank {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		for tile in self.tile_ram.iter() {
+			for row in tile {
+				w.write_bytes(row);
+			}
+		}
+		for map in self.tile_map.iter() {
+			w.write_bytes(map);
+		}
+		w.write_bool(self.accessible);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		for tile in self.tile_ram.iter_mut() {
+			for row in tile.iter_mut() {
+				try!(r.read_bytes(row));
+			}
+		}
+		for map in self.tile_map.iter_mut() {
+			try!(r.read_bytes(map));
+		}
+		self.accessible = try!(r.read_bool());
+		Ok(())
+	}
+}
+
+impl SaveState for OAM {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		for s in &self.sprite_ram {
+			w.write_bytes(&[s.y, s.x, s.tile, s.opt_data]);
+		}
+		w.write_bool(self.accessible);
+		w.write_bool(self.dma_transfer);
+		w.write_u16(self.dma_addr);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		let mut data = [0; 4];
+		for s in self.sprite_ram.iter_mut() {
+			try!(r.read_bytes(&mut data));
+			s.y = data[0];
+			s.x = data[1];
+			s.tile = data[2];
+			s.opt_data = data[3];
+			s.priority = data[3] & (1<<7) != 0;
+			s.y_flip = data[3] & (1<<6) != 0;
+			s.x_flip = data[3] & (1<<5) != 0;
+			s.palette_1_sel = data[3] & (1<<4) != 0;
+		}
+		self.accessible = try!(r.read_bool());
+		self.dma_transfer = try!(r.read_bool());
+		self.dma_addr = try!(r.read_u16());
+		Ok(())
+	}
+}
+
+impl SaveState for VideoData {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		let regs = &self.regs;
+		for reg in &[&regs.lcd_status, &regs.scy, &regs.scx, &regs.ly, &regs.lyc, &regs.wy, &regs.wx, &regs.bgp, &regs.obp0, &regs.obp1] {
+			reg.save_state(w);
+		}
+		w.write_u8(self.lcd_ctrl.read());
+		self.vram0.save_state(w);
+		self.oam.save_state(w);
+		w.write_u8(self.mode as u8);
+		w.write_u32(self.mode_cycles);
+		w.write_bytes(&self.back_buffer[..]);
+		w.write_bool(self.frame_ready);
+		w.write_u64(self.last_update);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		{
+			let regs = &mut self.regs;
+			for reg in &mut [&mut regs.lcd_status, &mut regs.scy, &mut regs.scx, &mut regs.ly, &mut regs.lyc, &mut regs.wy, &mut regs.wx, &mut regs.bgp, &mut regs.obp0, &mut regs.obp1] {
+				try!(reg.load_state(r));
+			}
+		}
+		//palettes are derived from the registers
+		let (bgp, obp0, obp1) = (*self.regs.bgp, *self.regs.obp0, *self.regs.obp1);
+		self.set_bg_palette(bgp);
+		self.set_obp0_palette(obp0);
+		self.set_obp1_palette(obp1);
+		self.lcd_ctrl.write(try!(r.read_u8()));
+		try!(self.vram0.load_state(r));
+		try!(self.oam.load_state(r));
+		self.mode = match try!(r.read_u8()) {
+			0 => HBLANK,
+			1 => VBLANK,
+			2 => ACCESS_OAM,
+			3 => ACCESS_VRAM,
+			_ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid video mode"))
+		};
+		self.mode_cycles = try!(r.read_u32());
+		try!(r.read_bytes(&mut self.back_buffer[..]));
+		self.frame_ready = try!(r.read_bool());
+		self.last_update = if r.version >= 4 { try!(r.read_u64()) } else { 0 };
+		Ok(())
+	}
+}
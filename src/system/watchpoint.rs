@@ -0,0 +1,59 @@
+use std::fmt;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum WatchKind {
+	Read,
+	Write,
+	//reads and writes
+	Access
+}
+
+//stops the debugger when the CPU accesses an address in start..=end,
+//if `value` is set only when that value is read or written
+#[derive(Debug,Copy,Clone)]
+pub struct Watchpoint {
+	pub start : u16,
+	pub end : u16,
+	pub kind : WatchKind,
+	pub value : Option<u8>
+}
+
+impl Watchpoint {
+
+	pub fn matches(&self, addr : u16, write : bool, value : u8) -> bool {
+		let kind_matches = match self.kind {
+			WatchKind::Read => !write,
+			WatchKind::Write => write,
+			WatchKind::Access => true
+		};
+		kind_matches && addr >= self.start && addr <= self.end && self.value.map_or(true, |v| v == value)
+	}
+}
+
+impl fmt::Display for Watchpoint {
+	fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+		let kind = match self.kind {
+			WatchKind::Read => "read",
+			WatchKind::Write => "write",
+			WatchKind::Access => "access"
+		};
+		try!(write!(f, "{} {:>04x}", kind, self.start));
+		if self.end != self.start {
+			try!(write!(f, "-{:>04x}", self.end));
+		}
+		if let Some(v) = self.value {
+			try!(write!(f, " = {:>02x}", v));
+		}
+		Ok(())
+	}
+}
+
+//an access that triggered a watchpoint. Reads have the same old and new value.
+#[derive(Debug,Copy,Clone)]
+pub struct WatchHit {
+	pub index : usize,
+	pub addr : u16,
+	pub write : bool,
+	pub old : u8,
+	pub new : u8
+}
@@ -1,4 +1,6 @@
+use std::io;
 use super::system::MemoryAccess;
+use savestate::{SaveState, StateWriter, StateReader};
 
 pub const WRAM_BANK_SIZE :usize = 4*1024; //4K per WRAM bank
 
@@ -31,4 +33,26 @@ impl MemoryAccess for ZeroPageRAM {
 	fn write(&mut self, addr: u16, data: u8) {
 		self.0[addr as usize] = data;
 	}
-}
\ No newline at end of file
+}
+
+impl SaveState for WRAMBank {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_bytes(&self.0[..]);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		r.read_bytes(&mut self.0[..])
+	}
+}
+
+impl SaveState for ZeroPageRAM {
+
+	fn save_state(&self, w : &mut StateWriter) {
+		w.write_bytes(&self.0[..]);
+	}
+
+	fn load_state(&mut self, r : &mut StateReader) -> io::Result<()> {
+		r.read_bytes(&mut self.0[..])
+	}
+}
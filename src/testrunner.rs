@@ -0,0 +1,225 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use core::cpu::CPU;
+use core::operands::Reg8Operand;
+use headless::Headless;
+use input::LiveInput;
+use rom::Rom;
+use system;
+use system::serial::{CaptureLink, NoLink};
+use system::video::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use image;
+
+const CYCLES_PER_FRAME : u64 = 70224;
+pub const DEFAULT_FRAME_LIMIT : u64 = 7200; //two minutes, enough for blargg's cpu_instrs
+
+//mooneye test ROMs load the Fibonacci numbers into B, C, D, E, H, L and execute `ld b,b` on success.
+//On failure all of them hold 0x42.
+const MOONEYE_PASS : [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL : [u8; 6] = [0x42; 6];
+const OPCODE_LD_B_B : u8 = 0x40;
+
+#[derive(Copy,Clone,PartialEq)]
+enum Check {
+	NotApplicable,
+	Pass,
+	Fail
+}
+
+impl Check {
+	fn label(&self) -> &'static str {
+		match *self {
+			Check::NotApplicable => "-",
+			Check::Pass => "pass",
+			Check::Fail => "FAIL"
+		}
+	}
+}
+
+struct TestResult {
+	name : String,
+	registers : Check,
+	serial : Check,
+	image : Check,
+	frames : u64,
+	error : Option<String>
+}
+
+impl TestResult {
+	fn passed(&self) -> bool {
+		self.error.is_none() &&
+			[self.registers, self.serial, self.image].iter().any(|c| *c == Check::Pass) &&
+			![self.registers, self.serial, self.image].iter().any(|c| *c == Check::Fail)
+	}
+
+	fn verdict(&self) -> &str {
+		if let Some(ref e) = self.error {
+			e
+		} else if self.passed() {
+			"PASSED"
+		} else if [self.registers, self.serial, self.image].iter().all(|c| *c == Check::NotApplicable) {
+			"TIMEOUT"
+		} else {
+			"FAILED"
+		}
+	}
+}
+
+//run every ROM in `dir` and print a pass/fail matrix. Returns true if all tests passed.
+pub fn run_directory(dir : &str, frame_limit : u64, jit : bool) -> io::Result<bool> {
+	let mut roms : Vec<PathBuf> = Vec::new();
+	for entry in try!(fs::read_dir(dir)) {
+		let path = try!(entry).path();
+		match path.extension().and_then(|e| e.to_str()) {
+			Some("gb") | Some("gbc") => roms.push(path),
+			_ => {}
+		}
+	}
+	roms.sort();
+
+	let mut results = Vec::new();
+	for path in &roms {
+		let result = run_test(path, frame_limit, jit);
+		println!("{}: {}", result.name, result.verdict());
+		results.push(result);
+	}
+
+	print_matrix(&results);
+	Ok(results.iter().all(|r| r.passed()))
+}
+
+fn run_test(path : &Path, frame_limit : u64, jit : bool) -> TestResult {
+	let mut result = TestResult {
+		name : path.file_name().unwrap().to_string_lossy().into_owned(),
+		registers : Check::NotApplicable,
+		serial : Check::NotApplicable,
+		image : Check::NotApplicable,
+		frames : 0,
+		error : None
+	};
+
+	let rom = match Rom::create_from_file(&path.to_string_lossy()) {
+		Ok(r) => r,
+		Err(e) => {
+			result.error = Some(format!("ERROR ({})", e));
+			return result
+		}
+	};
+	let reference = match load_reference(&path.with_extension("png")) {
+		Ok(r) => r,
+		Err(e) => {
+			result.error = Some(format!("ERROR (reference image: {})", e));
+			return result
+		}
+	};
+
+	let mut cpu = system::init(rom);
+	if jit {
+		cpu.enable_jit();
+	}
+	cpu.sys.set_serial_link(Box::new(CaptureLink::new(Box::new(NoLink), None)));
+	let mut headless = Headless::new(Box::new(LiveInput));
+	let mut serial_len = 0;
+
+	//the LCD may be off for a long time, so the limit counts cycles
+	while cpu.cycles < frame_limit*CYCLES_PER_FRAME {
+		if let Err(e) = cpu.run_instruction() {
+			result.error = Some(format!("ERROR ({})", e));
+			break
+		}
+
+		if headless.update(&mut cpu) {
+			if let Some(ref pixels) = reference {
+				if matches_reference(&cpu, pixels) {
+					result.image = Check::Pass;
+					break
+				}
+			}
+		}
+
+		let pc = cpu.regs.pc;
+		if cpu.fetch(pc)[0] == OPCODE_LD_B_B {
+			let regs = mooneye_registers(&cpu);
+			if regs == MOONEYE_PASS {
+				result.registers = Check::Pass;
+				break
+			} else if regs == MOONEYE_FAIL {
+				result.registers = Check::Fail;
+				break
+			} else if reference.is_some() {
+				//dmg-acid2 signals it is done drawing this way
+				break
+			}
+		}
+
+		let sys = &cpu.sys;
+		let output = sys.serial_output();
+		if output.len() != serial_len {
+			serial_len = output.len();
+			let text = String::from_utf8_lossy(output);
+			if text.contains("Passed") {
+				result.serial = Check::Pass;
+				break
+			} else if text.contains("Failed") {
+				result.serial = Check::Fail;
+				break
+			}
+		}
+	}
+	result.frames = headless.frames;
+
+	//the test finished or ran out of time, check what is on screen now
+	if let Some(ref pixels) = reference {
+		if result.image == Check::NotApplicable {
+			result.image = if matches_reference(&cpu, pixels) { Check::Pass } else { Check::Fail };
+		}
+	}
+	result
+}
+
+fn mooneye_registers(cpu : &CPU) -> [u8; 6] {
+	use core::operands::Reg8Operand::*;
+	let regs : [Reg8Operand; 6] = [b, c, d, e, h, l];
+	let mut values = [0; 6];
+	for (v, r) in values.iter_mut().zip(regs.iter()) {
+		*v = cpu.regs.get8(*r);
+	}
+	values
+}
+
+//reference images are compared by shade, so any four-level grayscale rendering works
+fn load_reference(path : &Path) -> io::Result<Option<Vec<u8>>> {
+	if !path.exists() {
+		return Ok(None)
+	}
+	let (width, height, rgb) = try!(image::read_png(path));
+	if width != SCREEN_WIDTH || height != SCREEN_HEIGHT {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "not 160x144"))
+	}
+	Ok(Some(rgb.chunks(3).map(shade).collect()))
+}
+
+fn matches_reference(cpu : &CPU, reference : &[u8]) -> bool {
+	let rgb = image::rgb332_to_rgb(&cpu.sys.video.back_buffer[..]);
+	rgb.chunks(3).map(shade).eq(reference.iter().cloned())
+}
+
+//0 = white .. 3 = black
+fn shade(rgb : &[u8]) -> u8 {
+	let brightness = (rgb[0] as u32 + rgb[1] as u32 + rgb[2] as u32) / 3;
+	((255 - brightness + 42) / 85) as u8
+}
+
+fn print_matrix(results : &[TestResult]) {
+	let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0).max(8);
+	println!("");
+	println!("{:<w$}  {:<6} {:<6} {:<6} {:>7}  {}", "ROM", "regs", "serial", "image", "frames", "result", w = width);
+	for r in results {
+		println!("{:<w$}  {:<6} {:<6} {:<6} {:>7}  {}", r.name, r.registers.label(), r.serial.label(),
+			r.image.label(), r.frames, r.verdict(), w = width);
+	}
+	let passed = results.iter().filter(|r| r.passed()).count();
+	println!("{}/{} passed", passed, results.len());
+}
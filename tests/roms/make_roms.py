@@ -0,0 +1,323 @@
+#!/usr/bin/env python3
+# Builds the test ROMs in this directory, and image.png, the screen image.gb has to show.
+# Run it after changing a ROM: python3 tests/roms/make_roms.py
+#
+# The ROMs report like mooneye's tests: B, C, D, E, H, L = 3, 5, 8, 13, 21, 34 and `ld b,b`
+# on success, all of them 0x42 and `ld b,b` on failure. serial.gb prints "Passed" over the
+# serial port, image.gb draws a picture and stops with `ld b,b`.
+
+import os
+import struct
+import zlib
+
+DIR = os.path.dirname(os.path.abspath(__file__))
+
+
+class Rom:
+	"""32K ROM without MBC. Code is emitted at the current address, labels can be used
+	before they are defined."""
+
+	def __init__(self, title):
+		self.data = bytearray(0x8000)
+		self.title = title
+		self.labels = {}
+		self.fixups = []
+		#entry point: nop; jp main
+		self.org(0x100)
+		self.emit(0x00)
+		self.abs16(0xc3, "main")
+		self.org(0x150)
+
+	def org(self, addr):
+		self.pc = addr
+
+	def emit(self, *data):
+		for b in data:
+			self.data[self.pc] = b
+			self.pc += 1
+
+	def label(self, name):
+		self.labels[name] = self.pc
+
+	#an instruction with a 16 bit address operand: jp, call, ld rr,nn
+	def abs16(self, opcode, label):
+		self.emit(opcode)
+		self.fixups.append((self.pc, label, "abs16"))
+		self.emit(0, 0)
+
+	#an instruction with the low byte of an address as operand
+	def low8(self, opcode, label):
+		self.emit(opcode)
+		self.fixups.append((self.pc, label, "low8"))
+		self.emit(0)
+
+	#jr with a condition or without (0x18)
+	def jr(self, opcode, label):
+		self.emit(opcode)
+		self.fixups.append((self.pc, label, "rel8"))
+		self.emit(0)
+
+	def save(self, name):
+		for addr, label, kind in self.fixups:
+			target = self.labels[label]
+			if kind == "rel8":
+				offset = target - (addr + 1)
+				assert -128 <= offset <= 127, label
+				self.data[addr] = offset & 0xff
+			elif kind == "low8":
+				self.data[addr] = target & 0xff
+			else:
+				self.data[addr:addr + 2] = struct.pack("<H", target)
+		self.data[0x134:0x134 + len(self.title)] = self.title
+		checksum = 0
+		for b in self.data[0x134:0x14d]:
+			checksum = (checksum - b - 1) & 0xff
+		self.data[0x14d] = checksum
+		self.data[0x14e:0x150] = struct.pack(">H", sum(self.data) & 0xffff)
+		with open(os.path.join(DIR, name), "wb") as f:
+			f.write(self.data)
+
+
+def passed(rom):
+	rom.emit(0x06, 3, 0x0e, 5, 0x16, 8)		# ld b,3; ld c,5; ld d,8
+	rom.emit(0x1e, 13, 0x26, 21, 0x2e, 34)	# ld e,13; ld h,21; ld l,34
+	rom.emit(0x40)							# ld b,b
+	rom.emit(0x18, 0xfe)					# jr $
+
+
+def failed(rom):
+	rom.label("fail")
+	rom.emit(0x3e, 0x42)					# ld a,$42
+	rom.emit(0x47, 0x4f, 0x57, 0x5f)		# ld b,a; ld c,a; ld d,a; ld e,a
+	rom.emit(0x67, 0x6f)					# ld h,a; ld l,a
+	rom.emit(0x40)							# ld b,b
+	rom.emit(0x18, 0xfe)					# jr $
+
+
+#enable the VBlank interrupt and make it pending, with IME off
+def request_vblank(rom):
+	rom.emit(0xf3)							# di
+	rom.emit(0x31, 0xfe, 0xff)				# ld sp,$fffe
+	rom.emit(0x3e, 0x01)					# ld a,1
+	rom.emit(0xe0, 0xff)					# ldh (IE),a
+	rom.emit(0xe0, 0x0f)					# ldh (IF),a
+
+
+#prints the zero terminated string at "message" over the serial port, then passes
+def serial_rom(name, message):
+	rom = Rom(b"TESTROM")
+	rom.label("main")
+	rom.abs16(0x21, "message")				# ld hl,message
+	rom.label("next")
+	rom.emit(0x2a)							# ld a,(hl+)
+	rom.emit(0xb7)							# or a
+	rom.jr(0x28, "done")					# jr z,done
+	rom.emit(0xe0, 0x01)					# ldh (SB),a
+	rom.emit(0x3e, 0x81)					# ld a,$81
+	rom.emit(0xe0, 0x02)					# ldh (SC),a  start with the internal clock
+	rom.label("wait")
+	rom.emit(0xf0, 0x02)					# ldh a,(SC)
+	rom.emit(0xcb, 0x7f)					# bit 7,a
+	rom.jr(0x20, "wait")					# jr nz,wait
+	rom.jr(0x18, "next")					# jr next
+	rom.label("done")
+	passed(rom)
+	rom.org(0x200)
+	rom.label("message")
+	rom.emit(*message)
+	rom.emit(0)
+	rom.save(name)
+
+
+#EI followed by DI never enables interrupts
+def ei_delay():
+	rom = Rom(b"CPUTEST")
+	rom.org(0x40)
+	rom.abs16(0xc3, "fail")					# jp fail
+	rom.org(0x150)
+	rom.label("main")
+	request_vblank(rom)
+	rom.emit(0xfb)							# ei
+	rom.emit(0xf3)							# di
+	passed(rom)
+	failed(rom)
+	rom.save("ei_delay.gb")
+
+
+#an interrupt pending at EI; HALT is taken with the HALT as the return address
+def ei_halt():
+	rom = Rom(b"CPUTEST")
+	rom.org(0x40)
+	rom.abs16(0xc3, "handler")				# jp handler
+	rom.org(0x150)
+	rom.label("main")
+	request_vblank(rom)
+	rom.emit(0x06, 0x02)					# ld b,2
+	rom.emit(0xfb)							# ei
+	rom.label("halt")
+	rom.emit(0x76)							# halt
+	rom.emit(0x78)							# ld a,b
+	rom.emit(0xfe, 0x03)					# cp 3
+	rom.abs16(0xc2, "fail")					# jp nz,fail
+	passed(rom)
+	rom.label("handler")
+	rom.emit(0x04)							# inc b
+	rom.emit(0xe1)							# pop hl
+	rom.emit(0x7d)							# ld a,l
+	rom.low8(0xfe, "halt")					# cp low(halt)  returns to the HALT
+	rom.abs16(0xc2, "fail")					# jp nz,fail
+	rom.emit(0x23)							# inc hl  don't halt again
+	rom.emit(0xe5)							# push hl
+	rom.emit(0xaf)							# xor a
+	rom.emit(0xe0, 0xff)					# ldh (IE),a
+	rom.emit(0xd9)							# reti
+	failed(rom)
+	rom.save("ei_halt.gb")
+
+
+#HALT with IME off and an interrupt pending doesn't halt, the next byte is read twice
+def halt_bug():
+	rom = Rom(b"CPUTEST")
+	rom.label("main")
+	request_vblank(rom)
+	rom.emit(0x06, 0x01)					# ld b,1
+	rom.emit(0x76)							# halt
+	rom.emit(0x04)							# inc b  runs twice
+	rom.emit(0x78)							# ld a,b
+	rom.emit(0xfe, 0x03)					# cp 3
+	rom.abs16(0xc2, "fail")					# jp nz,fail
+	passed(rom)
+	failed(rom)
+	rom.save("halt_bug.gb")
+
+
+#tiles for image.gb, 8 rows of 8 pixels, colors 0 (white) to 3 (black)
+TILES = [
+	[[0] * 8] * 8,
+	[[1] * 8] * 8,
+	[[2] * 8] * 8,
+	[[3] * 8] * 8,
+	#left half black: catches swapped bit order
+	[[3, 3, 3, 3, 0, 0, 0, 0]] * 8,
+	#diagonal line from the top left
+	[[3 if x == y else 0 for x in range(8)] for y in range(8)],
+	#the two bit planes differ in every column
+	[[(x + y) % 4 for x in range(8)] for y in range(8)],
+]
+
+SCREEN_TILES_X = 20
+SCREEN_TILES_Y = 18
+
+
+def image_tile(tx, ty):
+	if tx in (0, SCREEN_TILES_X - 1) or ty in (0, SCREEN_TILES_Y - 1):
+		return 3
+	return (tx * 3 + ty) % len(TILES)
+
+
+def tile_bytes(tile):
+	data = bytearray()
+	for row in tile:
+		low = sum(1 << (7 - x) for x in range(8) if row[x] & 1)
+		high = sum(1 << (7 - x) for x in range(8) if row[x] & 2)
+		data += bytes([low, high])
+	return data
+
+
+#draws TILES arranged by image_tile() with the LCD off, then shows two whole frames
+def image():
+	tiles = b"".join(tile_bytes(t) for t in TILES)
+	bg_map = bytearray(32 * 32)
+	for ty in range(SCREEN_TILES_Y):
+		for tx in range(SCREEN_TILES_X):
+			bg_map[ty * 32 + tx] = image_tile(tx, ty)
+
+	rom = Rom(b"TESTROM")
+	rom.label("main")
+	rom.emit(0xf3)							# di
+	rom.emit(0x31, 0xfe, 0xff)				# ld sp,$fffe
+	rom.label("vblank")
+	rom.emit(0xf0, 0x44)					# ldh a,(LY)
+	rom.emit(0xfe, 0x90)					# cp 144
+	rom.jr(0x20, "vblank")					# jr nz,vblank
+	rom.emit(0xaf)							# xor a
+	rom.emit(0xe0, 0x40)					# ldh (LCDC),a  LCD off
+	rom.abs16(0x21, "tiles")				# ld hl,tiles
+	rom.emit(0x11, 0x00, 0x80)				# ld de,$8000
+	rom.emit(0x01, *struct.pack("<H", len(tiles)))	# ld bc,len(tiles)
+	rom.abs16(0xcd, "copy")					# call copy
+	rom.abs16(0x21, "map")					# ld hl,map
+	rom.emit(0x11, 0x00, 0x98)				# ld de,$9800
+	rom.emit(0x01, 0x00, 0x04)				# ld bc,$400
+	rom.abs16(0xcd, "copy")					# call copy
+	rom.emit(0x3e, 0xe4)					# ld a,%11100100
+	rom.emit(0xe0, 0x47)					# ldh (BGP),a
+	rom.emit(0xaf)							# xor a
+	rom.emit(0xe0, 0x42)					# ldh (SCY),a
+	rom.emit(0xe0, 0x43)					# ldh (SCX),a
+	rom.emit(0x3e, 0x91)					# ld a,$91  LCD and BG on, tiles at 8000, map at 9800
+	rom.emit(0xe0, 0x40)					# ldh (LCDC),a
+	rom.emit(0x06, 0x02)					# ld b,2
+	rom.label("frame")
+	rom.label("leave_vblank")
+	rom.emit(0xf0, 0x44)					# ldh a,(LY)
+	rom.emit(0xfe, 0x90)					# cp 144
+	rom.jr(0x28, "leave_vblank")			# jr z,leave_vblank
+	rom.label("enter_vblank")
+	rom.emit(0xf0, 0x44)					# ldh a,(LY)
+	rom.emit(0xfe, 0x90)					# cp 144
+	rom.jr(0x20, "enter_vblank")			# jr nz,enter_vblank
+	rom.emit(0x05)							# dec b
+	rom.jr(0x20, "frame")					# jr nz,frame
+	rom.emit(0x40)							# ld b,b  done drawing
+	rom.emit(0x18, 0xfe)					# jr $
+
+	#copy bc bytes from hl to de
+	rom.label("copy")
+	rom.emit(0x2a)							# ld a,(hl+)
+	rom.emit(0x12)							# ld (de),a
+	rom.emit(0x13)							# inc de
+	rom.emit(0x0b)							# dec bc
+	rom.emit(0x78)							# ld a,b
+	rom.emit(0xb1)							# or c
+	rom.jr(0x20, "copy")					# jr nz,copy
+	rom.emit(0xc9)							# ret
+
+	rom.org(0x1000)
+	rom.label("tiles")
+	rom.emit(*tiles)
+	rom.org(0x2000)
+	rom.label("map")
+	rom.emit(*bg_map)
+	rom.save("image.gb")
+
+	#the reference is drawn from the same tiles here, not by the emulator
+	shades = [0xff, 0xaa, 0x55, 0x00]
+	rows = []
+	for y in range(SCREEN_TILES_Y * 8):
+		row = bytearray([0])	#no filter
+		for x in range(SCREEN_TILES_X * 8):
+			tile = TILES[image_tile(x // 8, y // 8)]
+			row.append(shades[tile[y % 8][x % 8]])
+		rows.append(bytes(row))
+	write_png("image.png", SCREEN_TILES_X * 8, SCREEN_TILES_Y * 8, b"".join(rows))
+
+
+#8 bit grayscale
+def write_png(name, width, height, scanlines):
+	def chunk(kind, data):
+		return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data) & 0xffffffff)
+	header = struct.pack(">IIBBBBB", width, height, 8, 0, 0, 0, 0)
+	with open(os.path.join(DIR, name), "wb") as f:
+		f.write(b"\x89PNG\r\n\x1a\n")
+		f.write(chunk(b"IHDR", header))
+		f.write(chunk(b"IDAT", zlib.compress(scanlines, 9)))
+		f.write(chunk(b"IEND", b""))
+
+
+serial_rom("serial.gb", b"Passed")
+serial_rom("registers.gb", b"")
+ei_delay()
+ei_halt()
+halt_bug()
+image()
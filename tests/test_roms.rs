@@ -0,0 +1,25 @@
+use std::process::Command;
+
+//the ROMs in tests/roms report their result over the serial port, with the mooneye register
+//signature or by drawing the screen next to them in a .png. tests/roms/make_roms.py builds them.
+fn run_test_roms(jit : bool) {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_rustyboy"));
+	command.arg("--test-roms").arg(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms"));
+	if jit {
+		command.arg("--jit");
+	}
+	let output = command.output().unwrap();
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "test ROMs failed:\n{}", stdout);
+}
+
+#[test]
+fn test_roms() {
+	run_test_roms(false);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_roms_jit() {
+	run_test_roms(true);
+}